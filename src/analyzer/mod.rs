@@ -0,0 +1,612 @@
+use crate::{
+    evaluator::{Number, SideValues},
+    parser::{nesting_error, BinOp, Condition, Expr, Link, Modifier, RelOp, Sides, MAX_NESTING},
+    program::DynError,
+};
+
+/// Runs between parsing and evaluation. Folds constant arithmetic like `2 * 3`
+/// into a single number, and rejects expressions which can never be evaluated,
+/// like `2d6kh3` or `d[6..1]`, before any dice are rolled.
+pub fn analyze(expr: Expr) -> Result<Expr, DynError> {
+    analyze_nested(expr, 0)
+}
+
+/// Analyzes `expr`, which is `depth` levels deep in the whole expression.
+fn analyze_nested(expr: Expr, depth: usize) -> Result<Expr, DynError> {
+    if depth > MAX_NESTING {
+        return Err(nesting_error());
+    }
+
+    let depth = depth + 1;
+    let analyze = |expr| analyze_nested(expr, depth);
+
+    Ok(match expr {
+        Expr::Int(_) | Expr::Float(_) | Expr::Ident(_) => expr,
+        Expr::Call { name, args } => Expr::Call {
+            name,
+            args: analyze_all(args, depth)?,
+        },
+        Expr::Additive { .. } | Expr::Multiplicative { .. } | Expr::Logical { .. } => {
+            let (first, links) = expr.into_chain();
+            let mut expr = analyze(first)?;
+
+            for link in links {
+                expr = match link {
+                    Link::Additive(operator, right) => {
+                        fold(expr, operator, analyze(right)?, |left, operator, right| {
+                            Expr::Additive {
+                                left,
+                                operator,
+                                right,
+                            }
+                        })?
+                    }
+                    Link::Multiplicative(operator, right) => {
+                        fold(expr, operator, analyze(right)?, |left, operator, right| {
+                            Expr::Multiplicative {
+                                left,
+                                operator,
+                                right,
+                            }
+                        })?
+                    }
+                    Link::Logical(operator, right) => Expr::Logical {
+                        left: Box::new(expr),
+                        operator,
+                        right: Box::new(analyze(right)?),
+                    },
+                };
+            }
+
+            expr
+        }
+        Expr::Exponential {
+            left,
+            operator,
+            right,
+        } => fold(
+            analyze(*left)?,
+            operator,
+            analyze(*right)?,
+            |left, operator, right| Expr::Exponential {
+                left,
+                operator,
+                right,
+            },
+        )?,
+        Expr::Negate(expr) => {
+            let expr = analyze(*expr)?;
+
+            match constant(&expr) {
+                Some(value) => to_expr(value.neg()?),
+                None => Expr::Negate(Box::new(expr)),
+            }
+        }
+        Expr::Relational {
+            left,
+            operator,
+            right,
+        } => Expr::Relational {
+            left: Box::new(analyze(*left)?),
+            operator,
+            right: Box::new(analyze(*right)?),
+        },
+        Expr::Not(expr) => Expr::Not(Box::new(analyze(*expr)?)),
+        Expr::Repeat { count, expr } => Expr::Repeat {
+            count: Box::new(analyze(*count)?),
+            expr: Box::new(analyze(*expr)?),
+        },
+        Expr::Table { name, roll } => Expr::Table {
+            name,
+            roll: roll.map(|roll| analyze(*roll)).transpose()?.map(Box::new),
+        },
+        Expr::Where { expr, condition } => Expr::Where {
+            expr: Box::new(analyze(*expr)?),
+            condition: analyze_condition(condition, depth)?,
+        },
+        Expr::If {
+            condition,
+            then,
+            otherwise,
+        } => Expr::If {
+            condition: Box::new(analyze(*condition)?),
+            then: Box::new(analyze(*then)?),
+            otherwise: Box::new(analyze(*otherwise)?),
+        },
+        Expr::Roll {
+            rolls,
+            sides,
+            modifiers,
+        } => {
+            let roll = Expr::Roll {
+                rolls: Box::new(analyze(*rolls)?),
+                sides: analyze_sides(sides, depth)?,
+                modifiers: modifiers
+                    .into_iter()
+                    .map(|modifier| analyze_modifier(modifier, depth))
+                    .collect::<Result<_, _>>()?,
+            };
+
+            validate_roll(&roll).map_err(|e| format!("{} in `{}`", e, roll))?;
+            roll
+        }
+        Expr::Draw { count, sides } => {
+            let draw = Expr::Draw {
+                count: Box::new(analyze(*count)?),
+                sides: analyze_sides(sides, depth)?,
+            };
+
+            validate_draw(&draw).map_err(|e| format!("{} in `{}`", e, draw))?;
+            draw
+        }
+        Expr::Group { exprs, modifiers } => {
+            let group = Expr::Group {
+                exprs: analyze_all(exprs, depth)?,
+                modifiers: modifiers
+                    .into_iter()
+                    .map(|modifier| analyze_modifier(modifier, depth))
+                    .collect::<Result<_, _>>()?,
+            };
+
+            validate_group(&group).map_err(|e| format!("{} in `{}`", e, group))?;
+            group
+        }
+    })
+}
+
+fn analyze_all(exprs: Vec<Expr>, depth: usize) -> Result<Vec<Expr>, DynError> {
+    exprs
+        .into_iter()
+        .map(|expr| analyze_nested(expr, depth))
+        .collect()
+}
+
+fn analyze_sides(sides: Sides, depth: usize) -> Result<Sides, DynError> {
+    let analyze = |expr| analyze_nested(expr, depth);
+
+    Ok(match sides {
+        Sides::Expr(expr) => Sides::Expr(Box::new(analyze(*expr)?)),
+        Sides::Range { min, max } => Sides::Range {
+            min: Box::new(analyze(*min)?),
+            max: Box::new(analyze(*max)?),
+        },
+        Sides::Values(values) => Sides::Values(analyze_all(values, depth)?),
+        Sides::Weighted(weights) => Sides::Weighted(
+            weights
+                .into_iter()
+                .map(|(value, weight)| Ok((analyze(value)?, analyze(weight)?)))
+                .collect::<Result<_, DynError>>()?,
+        ),
+        Sides::Fudge | Sides::Percentile => sides,
+    })
+}
+
+fn analyze_modifier(modifier: Modifier, depth: usize) -> Result<Modifier, DynError> {
+    let analyze_amount = |amount: Box<Expr>| -> Result<_, DynError> {
+        Ok(Box::new(analyze_nested(*amount, depth)?))
+    };
+    let analyze_condition = |condition| analyze_condition(condition, depth);
+
+    Ok(match modifier {
+        Modifier::KeepHighest(amount) => Modifier::KeepHighest(analyze_amount(amount)?),
+        Modifier::KeepLowest(amount) => Modifier::KeepLowest(analyze_amount(amount)?),
+        Modifier::DropHighest(amount) => Modifier::DropHighest(analyze_amount(amount)?),
+        Modifier::DropLowest(amount) => Modifier::DropLowest(analyze_amount(amount)?),
+        Modifier::Reroll { amount, condition } => Modifier::Reroll {
+            amount: analyze_amount(amount)?,
+            condition: condition.map(analyze_condition).transpose()?,
+        },
+        Modifier::Explode {
+            amount,
+            condition,
+            compound,
+        } => Modifier::Explode {
+            amount: amount.map(analyze_amount).transpose()?,
+            condition: condition.map(analyze_condition).transpose()?,
+            compound,
+        },
+        Modifier::Unique | Modifier::SortAscending | Modifier::SortDescending => modifier,
+        Modifier::Min(amount) => Modifier::Min(analyze_amount(amount)?),
+        Modifier::Max(amount) => Modifier::Max(analyze_amount(amount)?),
+        Modifier::Bonus(amount) => Modifier::Bonus(analyze_amount(amount)?),
+        Modifier::Penalty(amount) => Modifier::Penalty(analyze_amount(amount)?),
+        Modifier::CriticalSuccess(condition) => {
+            Modifier::CriticalSuccess(condition.map(analyze_condition).transpose()?)
+        }
+        Modifier::CriticalFailure(condition) => {
+            Modifier::CriticalFailure(condition.map(analyze_condition).transpose()?)
+        }
+    })
+}
+
+fn analyze_condition(condition: Condition, depth: usize) -> Result<Condition, DynError> {
+    Ok(Condition::new(
+        condition.operator,
+        analyze_nested(*condition.value, depth)?,
+    ))
+}
+
+/// Folds `left operator right` if both sides are numbers, pointing errors like
+/// division by zero at the operation the same way evaluation does.
+fn fold(
+    left: Expr,
+    operator: BinOp,
+    right: Expr,
+    rebuild: fn(Box<Expr>, BinOp, Box<Expr>) -> Expr,
+) -> Result<Expr, DynError> {
+    let (Some(l), Some(r)) = (constant(&left), constant(&right)) else {
+        return Ok(rebuild(Box::new(left), operator, Box::new(right)));
+    };
+
+    let result = match operator {
+        BinOp::Add => l.add(r),
+        BinOp::Sub => l.sub(r),
+        BinOp::Mul => l.mul(r),
+        BinOp::Div => l.div(r),
+        BinOp::IntDiv => l.int_div(r),
+        BinOp::Mod => l.rem(r),
+        BinOp::Pow => l.pow(r),
+    }
+    .map_err(|e| format!("{} in `{} {} {}`", e, left, operator, right))?;
+
+    Ok(to_expr(result))
+}
+
+fn constant(expr: &Expr) -> Option<Number> {
+    match expr {
+        Expr::Int(v) => Some(Number::Int(*v)),
+        Expr::Float(v) => Some(Number::Float(*v)),
+        _ => None,
+    }
+}
+
+fn constant_int(expr: &Expr) -> Option<Result<i64, DynError>> {
+    constant(expr).map(Number::to_i64)
+}
+
+fn to_expr(number: Number) -> Expr {
+    match number {
+        Number::Int(v) => Expr::Int(v),
+        Number::Float(v) => Expr::Float(v),
+    }
+}
+
+/// The sides of a die, if they are known without evaluating anything.
+fn constant_sides(sides: &Sides) -> Result<Option<SideValues>, DynError> {
+    Ok(match sides {
+        Sides::Expr(expr) => constant_int(expr)
+            .transpose()?
+            .map(|max| SideValues::Range { min: 1, max }),
+        Sides::Range { min, max } => {
+            match (
+                constant_int(min).transpose()?,
+                constant_int(max).transpose()?,
+            ) {
+                (Some(min), Some(max)) => Some(SideValues::Range { min, max }),
+                _ => None,
+            }
+        }
+        Sides::Values(values) => values
+            .iter()
+            .map(|v| constant_int(v).transpose())
+            .collect::<Result<Option<Vec<_>>, _>>()?
+            .map(SideValues::Values),
+        Sides::Weighted(weights) => {
+            let mut sides = vec![];
+
+            for (value, weight) in weights {
+                match (
+                    constant_int(value).transpose()?,
+                    constant_int(weight).transpose()?,
+                ) {
+                    (_, Some(weight)) if weight < 0 => {
+                        return Err(format!("Cannot use negative weight {}", weight).into())
+                    }
+                    (Some(value), Some(weight)) => sides.push((value, weight as u64)),
+                    _ => return Ok(None),
+                }
+            }
+
+            Some(SideValues::Weighted(sides))
+        }
+        Sides::Fudge => Some(SideValues::Range { min: -1, max: 1 }),
+        Sides::Percentile => Some(SideValues::Range { min: 1, max: 100 }),
+    })
+}
+
+fn validate_roll(roll: &Expr) -> Result<(), DynError> {
+    let Expr::Roll {
+        rolls,
+        sides,
+        modifiers,
+    } = roll
+    else {
+        unreachable!("{roll:?} is not a roll");
+    };
+
+    let rolls = constant_int(rolls).transpose()?;
+
+    if rolls.is_some_and(|rolls| rolls < 0) {
+        return Err("Cannot roll a negative number of times".into());
+    }
+
+    let sides = constant_sides(sides)?;
+
+    if sides.as_ref().is_some_and(SideValues::is_empty) {
+        return Err("Cannot roll a die without sides".into());
+    }
+
+    for modifier in modifiers {
+        let (amount, verb, counts_dice) = match modifier {
+            Modifier::KeepHighest(amount) | Modifier::KeepLowest(amount) => {
+                (Some(amount), "keep", true)
+            }
+            Modifier::DropHighest(amount) | Modifier::DropLowest(amount) => {
+                (Some(amount), "drop", true)
+            }
+            Modifier::Reroll { amount, condition } => {
+                if let Some(sides) = &sides {
+                    let min = sides.bounds().map_or(0, |(min, _)| min);
+                    validate_condition(sides, condition.as_ref(), min, "reroll", false)?;
+                }
+
+                (Some(amount), "reroll", false)
+            }
+            Modifier::Explode {
+                amount, condition, ..
+            } => {
+                if let Some(sides) = &sides {
+                    let max = sides.bounds().map_or(0, |(_, max)| max);
+                    validate_condition(
+                        sides,
+                        condition.as_ref(),
+                        max,
+                        "explode",
+                        amount.is_none(),
+                    )?;
+                }
+
+                (amount.as_ref(), "explode", false)
+            }
+            Modifier::Bonus(amount) | Modifier::Penalty(amount) => {
+                validate_bonus(sides.as_ref(), modifiers)?;
+
+                if constant_int(amount)
+                    .transpose()?
+                    .is_some_and(|amount| amount < 0)
+                {
+                    return Err("Cannot roll a negative number of bonus or penalty dice".into());
+                }
+
+                continue;
+            }
+            Modifier::Unique => {
+                // dropped dice don't need to be unique, so the check needs every die to be kept
+                let keeps_all = !modifiers.iter().any(Modifier::is_keep_or_drop);
+
+                if let (Some(rolls), Some(sides), true) = (rolls, &sides, keeps_all) {
+                    if rolls as i128 > sides.distinct() {
+                        return Err(format!(
+                            "Cannot make {} dice unique with {} different sides",
+                            rolls,
+                            sides.distinct()
+                        )
+                        .into());
+                    }
+                }
+
+                continue;
+            }
+            Modifier::SortAscending
+            | Modifier::SortDescending
+            | Modifier::Min(_)
+            | Modifier::Max(_)
+            | Modifier::CriticalSuccess(_)
+            | Modifier::CriticalFailure(_) => continue,
+        };
+
+        let Some(amount) = amount.and_then(|amount| constant_int(amount)).transpose()? else {
+            continue;
+        };
+
+        match amount {
+            amount if amount < 0 && counts_dice => {
+                return Err(format!("Cannot {} a negative number of dice", verb).into());
+            }
+            amount if amount < 0 => {
+                return Err(format!("Cannot {} a negative number of times", verb).into());
+            }
+            amount if counts_dice && rolls.is_some_and(|rolls| amount > rolls) => {
+                return Err(format!("Cannot {} more dice than rolled", verb).into());
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Bonus and penalty dice need a percentile die, and cancel each other out,
+/// so only their difference should be rolled.
+fn validate_bonus(sides: Option<&SideValues>, modifiers: &[Modifier]) -> Result<(), DynError> {
+    if sides.is_some_and(|sides| !sides.is_percentile()) {
+        return Err("Bonus and penalty dice only work on percentile dice like `d%`".into());
+    }
+
+    let bonus = modifiers.iter().any(|m| matches!(m, Modifier::Bonus(_)));
+    let penalty = modifiers.iter().any(|m| matches!(m, Modifier::Penalty(_)));
+
+    if bonus && penalty {
+        return Err(
+            "Bonus and penalty dice cancel each other out, roll only their difference".into(),
+        );
+    }
+
+    Ok(())
+}
+
+fn validate_draw(draw: &Expr) -> Result<(), DynError> {
+    let Expr::Draw { count, sides } = draw else {
+        unreachable!("{draw:?} is not a draw");
+    };
+
+    let count = constant_int(count).transpose()?;
+
+    if count.is_some_and(|count| count < 0) {
+        return Err("Cannot draw a negative number of cards".into());
+    }
+
+    if let (Some(count), Some(cards)) = (count, constant_sides(sides)?) {
+        if count as i128 > cards.len() {
+            return Err("Cannot draw more cards than the deck has".into());
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_group(group: &Expr) -> Result<(), DynError> {
+    let Expr::Group { exprs, modifiers } = group else {
+        unreachable!("{group:?} is not a group");
+    };
+
+    for modifier in modifiers {
+        let (amount, verb) = match modifier {
+            Modifier::KeepHighest(amount) | Modifier::KeepLowest(amount) => (amount, "keep"),
+            Modifier::DropHighest(amount) | Modifier::DropLowest(amount) => (amount, "drop"),
+            _ => continue,
+        };
+
+        match constant_int(amount).transpose()? {
+            Some(amount) if amount < 0 => {
+                return Err(format!("Cannot {} a negative number of results", verb).into());
+            }
+            Some(amount) if amount as usize > exprs.len() => {
+                return Err(format!("Cannot {} more results than in the group", verb).into());
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Rejects a reroll or explode condition which every side satisfies, which would
+/// either always use up its amount or, without one, never stop.
+/// Without a condition, dice reroll or explode on `default`.
+fn validate_condition(
+    sides: &SideValues,
+    condition: Option<&Condition>,
+    default: i64,
+    verb: &str,
+    unbounded: bool,
+) -> Result<(), DynError> {
+    let (operator, value) = match condition {
+        Some(Condition { operator, value }) => match constant(value) {
+            Some(value) => (operator, value.to_f64()),
+            None => return Ok(()),
+        },
+        None => (&RelOp::Equals, default as f64),
+    };
+
+    if sides.count_matching(operator, value)? == sides.len() {
+        return Err(match unbounded {
+            true => format!("Cannot {} on every side, it would never stop", verb).into(),
+            false => format!("Cannot {} on every side", verb).into(),
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        lexer::tokenize,
+        parser::{on_main_stack, parse, Cursor},
+    };
+
+    fn analyze_str(source: &str) -> Result<String, DynError> {
+        let mut cursor = Cursor::new(tokenize(source)?);
+        Ok(analyze(parse(&mut cursor)?)?.to_string())
+    }
+
+    #[test]
+    fn test_rejects_deep_trees() {
+        // trees built without the parser are limited as well
+        let rejected = on_main_stack(|| {
+            let mut expr = Expr::Ident("x".to_string());
+
+            for _ in 0..MAX_NESTING + 1 {
+                expr = Expr::Not(Box::new(expr));
+            }
+
+            analyze(expr).is_err()
+        });
+
+        assert!(rejected);
+    }
+
+    #[test]
+    fn test_folds_constants() {
+        assert_eq!(analyze_str("1d20 + 2 * 3").unwrap(), "1d20 + 6");
+        assert_eq!(analyze_str("(1 + 1)d(2 ^ 3)").unwrap(), "2d8");
+        assert_eq!(analyze_str("-(2 + 1)").unwrap(), "-3");
+        assert_eq!(analyze_str("x * (7 // 2)").unwrap(), "x * 3");
+    }
+
+    #[test]
+    fn test_rejects_invalid_rolls() {
+        for source in [
+            "2d6k3",
+            "2d6dl3",
+            "1d0",
+            "d[6..1]",
+            "1d6!>0",
+            "1d1!",
+            "d[5, 5]!",
+            "1d6!>=1",
+            "1d6r<7",
+            "1d6kh(-1)",
+            "1 / (2 - 2)",
+            "{1d20, 1d20}kh3",
+            "1d20b1",
+            "1d%b1p1",
+            "7d6u",
+            "3d[1, 1, 2]u",
+            "d{1: 1, 6: -1}",
+            "d{1: 0, 6: 0}",
+            "d{1: 0, 6: 2}!",
+            "53 draw d[1..52]",
+        ] {
+            assert!(
+                analyze_str(source).is_err(),
+                "{} should be rejected",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_accepts_valid_rolls() {
+        for source in [
+            "2d6k2",
+            "d[1..1]",
+            "1d6!>5",
+            "df!",
+            "n d6k3",
+            "2d(x)k1",
+            "d%!",
+            "{1d20, 1d20}kh1",
+            "1d100p2",
+            "6d6u",
+            "7d6dl1u",
+            "52 draw d[1..52]",
+            "d{1: 1, 6: 4}!",
+            "3 draw d{1: 2, 2: 1}",
+        ] {
+            assert!(analyze_str(source).is_ok(), "{} should be accepted", source);
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use std::str::FromStr;
 
-use clap::{ArgGroup, Parser};
+use clap::{ArgGroup, Parser, Subcommand};
 
 /// Roll some dice using the command line.
 #[derive(Debug, Clone, Parser)]
@@ -8,6 +8,9 @@ use clap::{ArgGroup, Parser};
 #[command(group=ArgGroup::new("log").args(["verbose", "quiet"]).multiple(false))]
 #[command(group=ArgGroup::new("multi").args(["source", "amount"]).multiple(false))]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// The source file to read from. If not provided, read from stdin.
     #[arg(short, long)]
     pub source: Option<String>,
@@ -16,6 +19,10 @@ pub struct Cli {
     #[arg(short, long)]
     pub destination: Option<String>,
 
+    /// The config file to load macros from. Defaults to `~/.config/roll/config.toml`.
+    #[arg(short, long)]
+    pub config: Option<String>,
+
     /// Enable verbose logging.
     #[arg(short, long)]
     pub verbose: bool,
@@ -33,6 +40,16 @@ pub struct Cli {
     #[arg(short, long)]
     pub explain: bool,
 
+    /// Treat the input as text with `[[1d20 + 5]]` placeholders, and replace each
+    /// placeholder with its result.
+    #[arg(short, long)]
+    pub template: bool,
+
+    /// Keep drawn cards out of their decks for the whole session, instead of
+    /// shuffling every deck again for each line or evaluation.
+    #[arg(long)]
+    pub keep_decks: bool,
+
     /// The mode to evaluate the expression with. rng (default), avg, min, max, med, simavg:<iteration>.
     #[arg(short, long)]
     pub mode: Option<Mode>,
@@ -43,14 +60,25 @@ pub struct Cli {
     /// - `d6` - roll a 6-sided die
     /// - `d[3..6]` - roll a die with a range of sides (inclusive)
     /// - `d[3, 5, 7]` - roll a die with a set of sides
+    /// - `d{1: 1, 6: 4}` - roll a die whose sides come up as often as their weights
     /// - `df` - roll a fudge die (same as d[-1, 0, 1])
     /// - `2d8` - roll two 8-sided die
     /// - `2d6k or 2d6kh` - roll two 6-sided die and keep the highest
     /// - `2d6kl` - roll two 6-sided die and keep the lowest
     /// - `2d6d or 2d6dl` - roll two 6-sided die and drop the lowest
     /// - `2d6dh` - roll two 6-sided die and drop the highest
-    /// - `1d6!` - roll a 6-sided die and explode on 6
+    /// - `1d6!` - roll a 6-sided die and explode on 6, again for every 6
+    /// - `1d6!2` - roll a 6-sided die and explode on 6 at most twice
+    /// - `1d6!!` - explode like `1d6!`, but add the explosions into the die
     /// - `1d6r` - roll a 6-sided die and reroll on 1
+    /// - `3 draw d[1..52]` - draw three cards from a deck, without putting them back
+    /// - `5d10u` - roll five 10-sided dice which all show a different side
+    /// - `4d6s or 4d6sa` - sort the dice lowest first (`sd` for highest first)
+    /// - `1d20min10` - roll a 20-sided die and count anything below 10 as 10 (`max` to cap)
+    /// - `1d20cs>18` - mark rolls over 18 as critical successes (`cf` for failures)
+    /// - `d%` - roll a percentile die (same as d100), shown as tens + units
+    /// - `d%b` or `d%p2` - roll with a bonus die or two penalty dice (Call of Cthulhu)
+    /// - `{1d20 + 5, 1d20 + 5}kh1` - keep or drop whole results of a group
     /// ‎
     /// Conditionals:
     /// For reroll or explode, you can add a condition.
@@ -62,15 +90,49 @@ pub struct Cli {
     /// - `>=3` - greater than or equals 3
     /// - `<=3` - less than or equals 3
     /// ‎
+    /// Comparisons and conditionals:
+    /// - `1d20 + 5 >= 15` - true if the roll is at least 15 (`=`, `~=`, `<`, `<=`, `>`, `>=`)
+    /// - `a and b`, `a or b`, `not a` - combine comparisons
+    /// - `if 1d20 + 7 >= 15 then 2d6 + 4 else 0` - only roll damage on a hit
+    /// ‎
+    /// Lists:
+    /// - `6x(4d6dl)` or `repeat(6, 4d6dl)` - evaluate `4d6dl` six times and list the results
+    /// - `sum(...)`, `min(...)`, `max(...)`, `sort(...)` - work on numbers, lists and pools
+    /// - `table(encounters, 1d6)` - the entry of a random table from the config or `encounters.tbl`
+    /// ‎
+    /// Pools:
+    /// - `(8d6) where >3` - keep only the dice greater than 3
+    /// - `count(8d6 = 6)` - count the sixes
+    /// - `take(sort(8d6), 3)` - the three lowest dice
+    /// - `sets(7d10)` - matching sets like `[3x8, 2x4]`, also `widest(...)` and `highest(...)`
+    /// ‎
     /// Examples:
     /// - `1d20 + 5` - roll a 20-sided die and add 5
     /// - `(1d4)d4` - roll 1d4 number of d4s
     /// - `4d(1d4)` - roll 4 dice with 1d4 sides
     /// - `2d6kh!>=5` - roll two 6-sided die, keep highest, then explode on 5 or higher
+    /// - `attack + 2` - evaluate the `attack` macro from the config file and add 2
+    #[allow(clippy::doc_lazy_continuation)]
     #[arg(last = true, verbatim_doc_comment)]
     pub expression: Vec<String>,
 }
 
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// Rewrite expressions in canonical notation, like `2d6kh1!>=5` for `2d6k!>=5`.
+    /// Reads from source or stdin and writes to destination or stdout.
+    Fmt {
+        /// A file to format in place instead.
+        file: Option<String>,
+    },
+    /// Roll on a random table with rows like `1-3: 2d4 goblins`, using a die which
+    /// covers every row. Reads from source or stdin if no file is given.
+    Table {
+        /// The table file to roll on, like `encounters.tbl`.
+        file: Option<String>,
+    },
+}
+
 impl Cli {
     pub fn verbose<T, F: Fn() -> T>(&self, f: F) {
         if self.verbose {
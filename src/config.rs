@@ -0,0 +1,238 @@
+use std::{fs, io::ErrorKind};
+
+use toml::{Table, Value};
+
+use crate::{path_utils::get_path, program::DynError};
+
+const DEFAULT_PATH: &str = "~/.config/roll/config.toml";
+
+/// User configuration, read from `~/.config/roll/config.toml` by default.
+///
+/// ```toml
+/// [macros]
+/// attack = "1d20 + 7"
+/// fireball = "8d6"
+///
+/// [tables]
+/// encounters = "~/tables/encounters.tbl"
+/// loot = ["1-3: 2d6 copper", "4-6: 1d4 silver"]
+///
+/// [limits]
+/// dice = 1000
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Named expressions, as `(name, expression)` pairs.
+    pub macros: Vec<(String, String)>,
+    /// Random tables, as `(name, table)` pairs.
+    pub tables: Vec<(String, TableSource)>,
+    pub limits: Limits,
+}
+
+/// Where the rows of a random table come from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TableSource {
+    /// The path of a table file.
+    File(String),
+    /// The rows written out in the config, like `"1-3: goblin"`.
+    Rows(Vec<String>),
+}
+
+/// The highest `recursion` limit. Every call goes deeper into the stack, so a
+/// higher limit would overflow it instead of failing with an error.
+pub const MAX_RECURSION: u64 = 256;
+
+/// Bounds on how much work a single evaluation may do, so that an expression
+/// like `1000000000d6` fails with an error instead of exhausting memory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    /// The total number of dice rolled, including explosions and rerolls.
+    pub dice: u64,
+    /// The number of sides a single die may have. Unlimited by default, since
+    /// rolling a die doesn't require listing its sides.
+    pub sides: u64,
+    /// How many times a single die may explode or be rerolled.
+    pub depth: u64,
+    /// How deeply macros and functions may call into each other. This is what
+    /// stops a recursive function without a base case from looping forever.
+    /// At most `MAX_RECURSION`.
+    pub recursion: u64,
+    /// How long an evaluation may take, in milliseconds.
+    pub timeout_ms: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            dice: 1_000_000,
+            sides: u64::MAX,
+            depth: 100,
+            recursion: 64,
+            timeout_ms: 5_000,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config at `path`, or at the default location if no path is given.
+    /// A missing file at the default location is not an error.
+    pub fn load(path: Option<&str>) -> Result<Self, DynError> {
+        let full_path = get_path(path.unwrap_or(DEFAULT_PATH))?;
+
+        match fs::read_to_string(&full_path) {
+            Ok(contents) => Self::parse(&contents)
+                .map_err(|e| format!("Invalid config {:?}: {}", full_path, e).into()),
+            Err(e) if e.kind() == ErrorKind::NotFound && path.is_none() => Ok(Self::default()),
+            Err(e) => Err(format!("Failed to read config {:?}: {}", full_path, e).into()),
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, DynError> {
+        let table = contents.parse::<Table>()?;
+        let mut config = Self::default();
+
+        if let Some(macros) = table.get("macros") {
+            let macros = macros.as_table().ok_or("'macros' must be a table")?;
+
+            for (name, value) in macros {
+                let expression = match value {
+                    Value::String(s) => s.clone(),
+                    Value::Integer(i) => i.to_string(),
+                    Value::Float(f) => f.to_string(),
+                    _ => Err(format!("Macro '{}' must be an expression string", name))?,
+                };
+
+                config.macros.push((name.clone(), expression));
+            }
+        }
+
+        if let Some(tables) = table.get("tables") {
+            let tables = tables.as_table().ok_or("'tables' must be a table")?;
+
+            for (name, value) in tables {
+                let source = match value {
+                    Value::String(path) => TableSource::File(path.clone()),
+                    Value::Array(rows) => TableSource::Rows(
+                        rows.iter()
+                            .map(|row| row.as_str().map(str::to_string))
+                            .collect::<Option<_>>()
+                            .ok_or_else(|| {
+                                format!("The rows of table '{}' must be strings", name)
+                            })?,
+                    ),
+                    _ => Err(format!(
+                        "Table '{}' must be a file path or a list of rows",
+                        name
+                    ))?,
+                };
+
+                config.tables.push((name.clone(), source));
+            }
+        }
+
+        if let Some(limits) = table.get("limits") {
+            let limits = limits.as_table().ok_or("'limits' must be a table")?;
+
+            for (name, value) in limits {
+                let limit = match value {
+                    Value::Integer(i) if *i >= 0 => *i as u64,
+                    _ => Err(format!("Limit '{}' must be a non-negative integer", name))?,
+                };
+
+                match name.as_str() {
+                    "dice" => config.limits.dice = limit,
+                    "sides" => config.limits.sides = limit,
+                    "depth" => config.limits.depth = limit,
+                    "recursion" => config.limits.recursion = limit.min(MAX_RECURSION),
+                    "timeout_ms" => config.limits.timeout_ms = limit,
+                    _ => Err(format!("Unknown limit '{}'", name))?,
+                }
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_macros() {
+        let config = Config::parse(
+            r#"
+            [macros]
+            attack = "1d20+7"
+            bonus = 3
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.macros,
+            vec![
+                ("attack".to_string(), "1d20+7".to_string()),
+                ("bonus".to_string(), "3".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_empty() {
+        let config = Config::parse("").unwrap();
+        assert!(config.macros.is_empty());
+        assert_eq!(config.limits, Limits::default());
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        let config = Config::parse("[limits]\ndice = 50\ntimeout_ms = 100\nrecursion = 8").unwrap();
+
+        assert_eq!(config.limits.dice, 50);
+        assert_eq!(config.limits.timeout_ms, 100);
+        assert_eq!(config.limits.recursion, 8);
+        assert_eq!(config.limits.sides, Limits::default().sides);
+
+        let config = Config::parse("[limits]\nrecursion = 100000").unwrap();
+        assert_eq!(config.limits.recursion, MAX_RECURSION);
+
+        assert!(Config::parse("[limits]\ndice = -1").is_err());
+        assert!(Config::parse("[limits]\nfaces = 6").is_err());
+    }
+
+    #[test]
+    fn test_parse_tables() {
+        let config = Config::parse(
+            r#"
+            [tables]
+            encounters = "encounters.tbl"
+            loot = ["1-3: 2d6 copper", "4-6: 1d4 silver"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.tables,
+            vec![
+                (
+                    "encounters".to_string(),
+                    TableSource::File("encounters.tbl".to_string())
+                ),
+                (
+                    "loot".to_string(),
+                    TableSource::Rows(vec![
+                        "1-3: 2d6 copper".to_string(),
+                        "4-6: 1d4 silver".to_string()
+                    ])
+                ),
+            ]
+        );
+        assert!(Config::parse("[tables]\nloot = [1, 2]").is_err());
+    }
+
+    #[test]
+    fn test_parse_invalid_macro() {
+        assert!(Config::parse("[macros]\nattack = true").is_err());
+    }
+}
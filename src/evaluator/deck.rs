@@ -0,0 +1,185 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use rand::Rng;
+
+use crate::program::DynError;
+
+use super::{Env, SideValues};
+
+/// The cards drawn so far from every deck, by the notation of the deck like `d[1..52]`.
+/// A deck is a die whose sides can't come up again once they were drawn, so a bag
+/// of tokens like `d[1, 1, 1, 2]` has three `1`s to draw.
+#[derive(Debug, Default)]
+pub struct Decks {
+    decks: Mutex<HashMap<String, Deck>>,
+}
+
+#[derive(Debug, Default)]
+struct Deck {
+    drawn: Vec<i64>,
+    /// Ranges can be too big to list, so they are shuffled as they are drawn from.
+    /// The position `i` holds the card `min + i`, unless a card was swapped into it.
+    swapped: HashMap<i128, i64>,
+}
+
+impl Decks {
+    /// Draws `count` cards from the deck named `deck`, which has the cards `cards`.
+    pub fn draw<R: Rng>(
+        &self,
+        deck: &str,
+        cards: &SideValues,
+        count: usize,
+        env: &Env,
+        rng: &mut R,
+    ) -> Result<Vec<i64>, DynError> {
+        let mut decks = self.decks.lock().unwrap();
+        let deck_state = decks.entry(deck.to_string()).or_default();
+
+        let left = cards.len() - deck_state.drawn.len() as i128;
+
+        if count as i128 > left {
+            return Err(format!(
+                "Cannot draw {} from `{}` with only {} cards left",
+                count, deck, left
+            )
+            .into());
+        }
+
+        let hand = match cards {
+            SideValues::Range { min, .. } => {
+                deck_state.draw_from_range(*min, cards.len(), count, env, rng)?
+            }
+            _ => deck_state.draw_from_bag(cards, count, env, rng)?,
+        };
+
+        deck_state.drawn.extend(&hand);
+
+        Ok(hand)
+    }
+}
+
+impl Deck {
+    /// Draws by swapping a random card of the ones left to the front of the deck,
+    /// so only the positions which were swapped have to be remembered.
+    fn draw_from_range<R: Rng>(
+        &mut self,
+        min: i64,
+        len: i128,
+        count: usize,
+        env: &Env,
+        rng: &mut R,
+    ) -> Result<Vec<i64>, DynError> {
+        let card_at = |swapped: &HashMap<i128, i64>, i: i128| {
+            swapped.get(&i).copied().unwrap_or((min as i128 + i) as i64)
+        };
+
+        let mut hand = Vec::with_capacity(count);
+
+        for _ in 0..count {
+            env.check_time()?;
+
+            let front = self.drawn.len() as i128 + hand.len() as i128;
+            let picked = rng.gen_range(front..len);
+
+            let card = card_at(&self.swapped, picked);
+            let front_card = card_at(&self.swapped, front);
+
+            self.swapped.insert(picked, front_card);
+            self.swapped.remove(&front);
+
+            hand.push(card);
+        }
+
+        Ok(hand)
+    }
+
+    fn draw_from_bag<R: Rng>(
+        &mut self,
+        cards: &SideValues,
+        count: usize,
+        env: &Env,
+        rng: &mut R,
+    ) -> Result<Vec<i64>, DynError> {
+        let mut left = cards.weights();
+
+        for card in &self.drawn {
+            if let Some((_, weight)) = left.iter_mut().find(|(v, w)| v == card && *w > 0) {
+                *weight -= 1;
+            }
+        }
+
+        let mut total = left.iter().map(|(_, w)| *w as u128).sum::<u128>();
+        let mut hand = Vec::with_capacity(count);
+
+        for _ in 0..count {
+            env.check_time()?;
+
+            let mut n = rng.gen_range(0..total);
+            let (card, weight) = left
+                .iter_mut()
+                .find(|(_, w)| match n.checked_sub(*w as u128) {
+                    Some(rest) => {
+                        n = rest;
+                        false
+                    }
+                    None => true,
+                })
+                .expect("Picked a card past the last one");
+
+            *weight -= 1;
+            total -= 1;
+            hand.push(*card);
+        }
+
+        Ok(hand)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cli::Cli, config::Limits, evaluator::Definitions};
+
+    use clap::Parser;
+
+    /// Draws with the default limits, like a single evaluation would.
+    fn draw(
+        decks: &Decks,
+        deck: &str,
+        cards: &SideValues,
+        count: usize,
+    ) -> Result<Vec<i64>, DynError> {
+        let (cli, definitions, limits) = (
+            Cli::parse_from(["roll"]),
+            Definitions::default(),
+            Limits::default(),
+        );
+        let env = Env::new(&cli, &definitions, &limits, decks);
+
+        decks.draw(deck, cards, count, &env, &mut rand::thread_rng())
+    }
+
+    #[test]
+    fn test_draw_without_replacement() {
+        let decks = Decks::default();
+        let cards = SideValues::Range { min: 1, max: 10 };
+
+        let mut hand = draw(&decks, "d[1..10]", &cards, 6).unwrap();
+        hand.extend(draw(&decks, "d[1..10]", &cards, 4).unwrap());
+        hand.sort();
+
+        assert_eq!(hand, (1..=10).collect::<Vec<_>>());
+        assert!(draw(&decks, "d[1..10]", &cards, 1).is_err());
+    }
+
+    #[test]
+    fn test_draw_from_bag() {
+        let decks = Decks::default();
+        let bag = SideValues::Values(vec![1, 1, 1, 2]);
+
+        let mut hand = draw(&decks, "d[1, 1, 1, 2]", &bag, 4).unwrap();
+        hand.sort();
+
+        assert_eq!(hand, vec![1, 1, 1, 2]);
+    }
+}
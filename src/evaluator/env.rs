@@ -0,0 +1,174 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{cli::Cli, config::Limits, parser::Expr, program::DynError};
+
+use super::{is_builtin, Decks, EvalResult, Table};
+
+/// A named expression. Macros are definitions without parameters.
+#[derive(Debug, Clone)]
+pub struct Definition {
+    pub params: Vec<String>,
+    pub body: Expr,
+}
+
+/// Named expressions that can be referred to by identifier or called,
+/// and the random tables which can be rolled on by name.
+#[derive(Debug, Clone, Default)]
+pub struct Definitions {
+    definitions: HashMap<String, Definition>,
+    tables: HashMap<String, Arc<Table>>,
+    /// Tables read from `name.tbl` the first time they were rolled on.
+    loaded_tables: Arc<Mutex<HashMap<String, Arc<Table>>>>,
+}
+
+impl Definitions {
+    pub fn define(
+        &mut self,
+        name: String,
+        params: Vec<String>,
+        body: Expr,
+    ) -> Result<(), DynError> {
+        if is_builtin(&name) {
+            return Err(format!("Cannot redefine builtin function '{}'", name).into());
+        }
+
+        self.definitions.insert(name, Definition { params, body });
+        Ok(())
+    }
+
+    pub fn define_table(&mut self, name: String, table: Table) {
+        self.tables.insert(name, Arc::new(table));
+    }
+}
+
+/// The work done so far by one evaluation, shared by every environment created from it.
+#[derive(Debug)]
+struct Usage {
+    dice: AtomicU64,
+    started: Instant,
+}
+
+/// Everything an expression needs while it is being evaluated.
+/// A new environment should be created for every evaluation, since the limits apply to each one.
+#[derive(Debug, Clone)]
+pub struct Env<'a> {
+    pub cli: &'a Cli,
+    pub limits: &'a Limits,
+    pub decks: &'a Decks,
+    definitions: &'a Definitions,
+    locals: HashMap<String, EvalResult>,
+    depth: usize,
+    usage: Arc<Usage>,
+}
+
+impl<'a> Env<'a> {
+    /// `decks` are the cards drawn so far, which can be kept between evaluations.
+    pub fn new(
+        cli: &'a Cli,
+        definitions: &'a Definitions,
+        limits: &'a Limits,
+        decks: &'a Decks,
+    ) -> Self {
+        Self {
+            cli,
+            limits,
+            decks,
+            definitions,
+            locals: HashMap::new(),
+            depth: 0,
+            usage: Arc::new(Usage {
+                dice: AtomicU64::new(0),
+                started: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn get_local(&self, name: &str) -> Option<&EvalResult> {
+        self.locals.get(name)
+    }
+
+    pub fn get_definition(&self, name: &str) -> Option<&'a Definition> {
+        self.definitions.definitions.get(name)
+    }
+
+    /// The table `name`. Tables which aren't defined in the config are read from
+    /// `name.tbl`, only once for every run.
+    pub fn get_table(&self, name: &str) -> Result<Arc<Table>, DynError> {
+        if let Some(table) = self.definitions.tables.get(name) {
+            return Ok(table.clone());
+        }
+
+        let mut loaded = self.definitions.loaded_tables.lock().unwrap();
+
+        if let Some(table) = loaded.get(name) {
+            return Ok(table.clone());
+        }
+
+        let table = Table::load(&format!("{}.tbl", name))
+            .map_err(|e| format!("Unknown table '{}': {}", name, e))?;
+        let table = Arc::new(table);
+
+        loaded.insert(name.to_string(), table.clone());
+        Ok(table)
+    }
+
+    /// Creates the environment for evaluating the body of the definition `name`,
+    /// with its parameters bound to `locals`.
+    pub fn enter(
+        &self,
+        name: &str,
+        locals: HashMap<String, EvalResult>,
+    ) -> Result<Env<'a>, DynError> {
+        if self.depth as u64 >= self.limits.recursion {
+            return Err(format!(
+                "Maximum recursion depth of {} exceeded in '{}'",
+                self.limits.recursion, name
+            )
+            .into());
+        }
+
+        Ok(Self {
+            cli: self.cli,
+            limits: self.limits,
+            decks: self.decks,
+            definitions: self.definitions,
+            locals,
+            depth: self.depth + 1,
+            usage: self.usage.clone(),
+        })
+    }
+
+    /// Records that `amount` more dice are about to be rolled.
+    pub fn count_dice(&self, amount: u64) -> Result<(), DynError> {
+        let rolled = self.usage.dice.fetch_add(amount, Ordering::Relaxed);
+
+        if rolled.saturating_add(amount) > self.limits.dice {
+            return Err(format!(
+                "Rolling {} more dice would exceed the limit of {} dice",
+                amount, self.limits.dice
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+
+    pub fn check_time(&self) -> Result<(), DynError> {
+        if self.usage.started.elapsed() > Duration::from_millis(self.limits.timeout_ms) {
+            return Err(format!(
+                "Evaluation took longer than the limit of {}ms",
+                self.limits.timeout_ms
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+}
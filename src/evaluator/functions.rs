@@ -0,0 +1,141 @@
+use crate::program::DynError;
+
+use super::{
+    value::{group_sets, sum},
+    EvalResult, Number, Value,
+};
+
+/// Whether `name` refers to a function which is built into the language.
+pub fn is_builtin(name: &str) -> bool {
+    matches!(
+        name,
+        "min"
+            | "max"
+            | "sum"
+            | "sort"
+            | "count"
+            | "take"
+            | "sets"
+            | "widest"
+            | "highest"
+            | "width"
+            | "height"
+            | "repeat"
+            | "table"
+    )
+}
+
+/// Calls the builtin function `name` with already evaluated arguments.
+/// Lists and pools passed as arguments are flattened, so `max(6x(1d20))`,
+/// `max(2d20)` and `max(1d20, 1d20)` all work.
+pub fn call_builtin(name: &str, args: Vec<EvalResult>) -> Result<EvalResult, DynError> {
+    let explanation = args
+        .iter()
+        .map(|a| a.explanation.clone())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let explanation = format!("{}({})", name, explanation);
+
+    match (name, args.as_slice()) {
+        ("count", [arg]) => {
+            return Ok(EvalResult {
+                result: Value::Number(Number::Int(arg.result.count()? as i64)),
+                explanation,
+                is_roll: false,
+            });
+        }
+        ("take", [arg, n]) => {
+            let n = n.result.as_int()?;
+
+            if n < 0 {
+                return Err("Cannot take a negative number of values".into());
+            }
+
+            return Ok(EvalResult {
+                result: arg.result.take(n as usize)?,
+                explanation,
+                is_roll: false,
+            });
+        }
+        ("width" | "height", [arg]) => {
+            let Value::Set { width, height } = arg.result else {
+                return Err(format!("'{}' expects a set, found {}", name, arg.result).into());
+            };
+
+            let result = if name == "width" {
+                Number::Int(width as i64)
+            } else {
+                height
+            };
+
+            return Ok(EvalResult {
+                result: Value::Number(result),
+                explanation,
+                is_roll: false,
+            });
+        }
+        ("count", _) => return Err("'count' expects a pool or a list".into()),
+        ("width" | "height", _) => return Err(format!("'{}' expects a set", name).into()),
+        ("take", _) => return Err("'take' expects a pool or a list and an amount".into()),
+        _ => {}
+    }
+
+    let mut values = vec![];
+
+    for arg in &args {
+        values.extend(arg.result.numbers()?);
+    }
+
+    let result = match name {
+        "min" | "max" if values.is_empty() => {
+            return Err(format!("'{}' expects at least one value", name).into());
+        }
+        "min" => Value::Number(values.into_iter().min_by(|a, b| a.total_cmp(b)).unwrap()),
+        "max" => Value::Number(values.into_iter().max_by(|a, b| a.total_cmp(b)).unwrap()),
+        "sum" => Value::Number(sum(&values)?),
+        "sort" => {
+            values.sort_by(|a, b| a.total_cmp(b));
+            Value::List(values.into_iter().map(Value::Number).collect())
+        }
+        // single dice aren't a match, so only sets of two or more are listed
+        "sets" => Value::List(
+            group_sets(&values)
+                .into_iter()
+                .filter(|(width, _)| *width > 1)
+                .map(|(width, height)| Value::Set { width, height })
+                .collect(),
+        ),
+        // a roll without any matches still has a widest and highest set of width 1
+        "widest" | "highest" => {
+            let sets = group_sets(&values);
+            let has_matches = sets.first().is_some_and(|(width, _)| *width > 1);
+
+            let best = if name == "widest" {
+                sets.first()
+            } else {
+                sets.iter()
+                    .filter(|(width, _)| !has_matches || *width > 1)
+                    .max_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)))
+            };
+
+            let (width, height) =
+                *best.ok_or_else(|| format!("'{}' expects at least one die", name))?;
+
+            Value::Set { width, height }
+        }
+        "repeat" => {
+            return Err("'repeat' expects a count and an expression, like repeat(6, 4d6dl)".into());
+        }
+        "table" => {
+            return Err("'table' expects the name of a table, like table(encounters, 1d6)".into());
+        }
+        other => unreachable!("{other} is not a builtin function"),
+    };
+
+    Ok(EvalResult {
+        result,
+        explanation,
+        is_roll: false,
+    })
+}
@@ -1,206 +1,613 @@
+mod deck;
+mod env;
+mod functions;
 mod mode;
+mod number;
+mod percentile;
+mod sides;
+mod table;
+mod value;
 
-use mode::Mode;
+use std::collections::HashMap;
+
+pub use deck::Decks;
+pub use env::{Definitions, Env};
+pub use functions::is_builtin;
+use mode::{rel_op_eval_value, roll_plain, Mode};
+pub use number::Number;
+pub use sides::SideValues;
+pub use table::Table;
+pub use value::Value;
 
 use crate::{
-    cli::{self, Cli},
-    parser::{BinOp, Expr, Modifier, Sides},
+    cli,
+    parser::{BinOp, Condition, Expr, Link, LogicOp, Modifier, RelOp, Sides},
     program::DynError,
 };
 
-pub fn eval(tree: &Expr, cli: &Cli) -> Result<EvalResult, DynError> {
+pub fn eval(tree: &Expr, env: &Env) -> Result<EvalResult, DynError> {
+    env.check_time()?;
+
     match tree {
         Expr::Int(v) => Ok(EvalResult {
-            result: *v as f64,
+            result: Value::Number(Number::Int(*v)),
             explanation: v.to_string(),
             is_roll: false,
         }),
         Expr::Float(v) => Ok(EvalResult {
-            result: *v,
+            result: Value::Number(Number::Float(*v)),
             explanation: v.to_string(),
             is_roll: false,
         }),
-        Expr::Additive {
+        Expr::Ident(name) => eval_ident(name, env),
+        Expr::Call { name, args } => eval_call(name, args, env),
+        Expr::Additive { .. } | Expr::Multiplicative { .. } | Expr::Logical { .. } => {
+            eval_chain(tree, env)
+        }
+        Expr::Exponential {
             left,
             operator,
             right,
-        } => eval_additive(left, operator, right, cli),
-        Expr::Multiplicative {
+        } => eval_exponential(left, operator, right, env),
+        Expr::Negate(expr) => {
+            let EvalResult {
+                result,
+                explanation,
+                ..
+            } = eval(expr, env)?;
+
+            Ok(EvalResult {
+                result: Value::Number(result.as_number()?.neg()?),
+                explanation: format!("-{}", explanation),
+                is_roll: false,
+            })
+        }
+        Expr::Relational {
             left,
             operator,
             right,
-        } => eval_multiplicative(left, operator, right, cli),
+        } => eval_relational(left, operator, right, env),
+        Expr::Not(expr) => {
+            let EvalResult {
+                result,
+                explanation,
+                ..
+            } = eval(expr, env)?;
+
+            Ok(EvalResult {
+                result: Value::Bool(!result.as_bool()?),
+                explanation: format!("not {}", explanation),
+                is_roll: false,
+            })
+        }
+        Expr::If {
+            condition,
+            then,
+            otherwise,
+        } => eval_if(condition, then, otherwise, env),
+        Expr::Repeat { count, expr } => eval_repeat(count, expr, env),
+        Expr::Where { expr, condition } => eval_where(expr, condition, env),
         Expr::Roll {
             rolls,
             sides,
             modifiers,
-        } => eval_roll(rolls, sides, modifiers, cli),
+        } => eval_roll(rolls, sides, modifiers, env),
+        Expr::Group { exprs, modifiers } => eval_group(exprs, modifiers, env),
+        Expr::Draw { count, sides } => eval_draw(count, sides, env),
+        Expr::Table { name, roll } => eval_table(name, roll.as_deref(), env),
+    }
+}
+
+fn eval_ident(name: &str, env: &Env) -> Result<EvalResult, DynError> {
+    if let Some(value) = env.get_local(name) {
+        return Ok(value.clone());
+    }
+
+    eval_call(name, &[], env)
+}
+
+fn eval_call(name: &str, args: &[Expr], env: &Env) -> Result<EvalResult, DynError> {
+    let args = args
+        .iter()
+        .map(|a| match (name, a) {
+            // inside `count`, a comparison keeps the dice that match, so `count(8d6 = 6)` counts sixes
+            (
+                "count",
+                Expr::Relational {
+                    left,
+                    operator,
+                    right,
+                },
+            ) => eval_filter(left, operator, right, env),
+            _ => eval(a, env),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let Some(definition) = env.get_definition(name) else {
+        if functions::is_builtin(name) {
+            return functions::call_builtin(name, args);
+        }
+
+        return Err(format!("Unknown identifier '{}'", name).into());
+    };
+
+    if definition.params.len() != args.len() {
+        return Err(format!(
+            "'{}' expects {} argument(s), found {}",
+            name,
+            definition.params.len(),
+            args.len()
+        )
+        .into());
+    }
+
+    let locals = definition
+        .params
+        .iter()
+        .cloned()
+        .zip(args)
+        .collect::<HashMap<_, _>>();
+
+    let EvalResult {
+        result,
+        explanation,
+        is_roll,
+    } = eval(&definition.body, &env.enter(name, locals)?)?;
+
+    Ok(EvalResult {
+        result,
+        explanation: format!("{}({})", name, explanation),
+        is_roll,
+    })
+}
+
+/// Evaluates a chain like `1d6 + 1d6 + 1d6` from left to right, one operator
+/// at a time, so long chains don't recurse once per operator.
+fn eval_chain(chain: &Expr, env: &Env) -> Result<EvalResult, DynError> {
+    let (first, links) = chain.chain();
+    let mut result = eval(first, env)?;
+
+    for link in links {
+        result = match link {
+            Link::Additive(operator, right) => eval_additive(result, &operator, right, env)?,
+            Link::Multiplicative(operator, right) => {
+                eval_multiplicative(result, &operator, right, env)?
+            }
+            Link::Logical(operator, right) => eval_logical(result, &operator, right, env)?,
+        };
     }
+
+    Ok(result)
 }
 
 fn eval_additive(
-    left: &Expr,
+    left: EvalResult,
     operator: &BinOp,
     right: &Expr,
-    cli: &Cli,
+    env: &Env,
 ) -> Result<EvalResult, DynError> {
     let EvalResult {
         result: left,
         explanation: left_explanation,
         ..
-    } = eval(left, cli)?;
+    } = left;
 
     let EvalResult {
         result: right,
         explanation: right_explanation,
         ..
-    } = eval(right, cli)?;
+    } = eval(right, env)?;
 
     Ok(EvalResult {
-        result: match operator {
-            BinOp::Add => left + right,
-            BinOp::Sub => left - right,
-            other => unreachable!("{other:?} is not an additive operator"),
-        },
+        result: Value::Number(
+            match operator {
+                BinOp::Add => left.as_number()?.add(right.as_number()?),
+                BinOp::Sub => left.as_number()?.sub(right.as_number()?),
+                other => unreachable!("{other:?} is not an additive operator"),
+            }
+            .map_err(|e| operation_error(e, &left_explanation, operator, &right_explanation))?,
+        ),
         explanation: format!("{} {} {}", left_explanation, operator, right_explanation),
         is_roll: false,
     })
 }
 
 fn eval_multiplicative(
+    left: EvalResult,
+    operator: &BinOp,
+    right: &Expr,
+    env: &Env,
+) -> Result<EvalResult, DynError> {
+    let EvalResult {
+        result: left,
+        explanation: left_explanation,
+        ..
+    } = left;
+
+    let EvalResult {
+        result: right,
+        explanation: right_explanation,
+        ..
+    } = eval(right, env)?;
+
+    Ok(EvalResult {
+        result: Value::Number(
+            match operator {
+                BinOp::Mul => left.as_number()?.mul(right.as_number()?),
+                BinOp::Div => left.as_number()?.div(right.as_number()?),
+                BinOp::IntDiv => left.as_number()?.int_div(right.as_number()?),
+                BinOp::Mod => left.as_number()?.rem(right.as_number()?),
+                other => unreachable!("{other:?} is not an additive operator"),
+            }
+            .map_err(|e| operation_error(e, &left_explanation, operator, &right_explanation))?,
+        ),
+        explanation: format!("{} {} {}", left_explanation, operator, right_explanation),
+        is_roll: false,
+    })
+}
+
+fn eval_exponential(
     left: &Expr,
     operator: &BinOp,
     right: &Expr,
-    cli: &Cli,
+    env: &Env,
 ) -> Result<EvalResult, DynError> {
     let EvalResult {
         result: left,
         explanation: left_explanation,
         ..
-    } = eval(left, cli)?;
+    } = eval(left, env)?;
 
     let EvalResult {
         result: right,
         explanation: right_explanation,
         ..
-    } = eval(right, cli)?;
+    } = eval(right, env)?;
 
     Ok(EvalResult {
-        result: match operator {
-            BinOp::Mul => left * right,
-            BinOp::Div => left / right,
-            BinOp::Mod => left % right,
-            other => unreachable!("{other:?} is not an additive operator"),
-        },
+        result: Value::Number(
+            match operator {
+                BinOp::Pow => left.as_number()?.pow(right.as_number()?),
+                other => unreachable!("{other:?} is not an exponential operator"),
+            }
+            .map_err(|e| operation_error(e, &left_explanation, operator, &right_explanation))?,
+        ),
         explanation: format!("{} {} {}", left_explanation, operator, right_explanation),
         is_roll: false,
     })
 }
 
+/// Points an arithmetic error at the operation that caused it.
+fn operation_error(error: DynError, left: &str, operator: &BinOp, right: &str) -> String {
+    format!("{} in `{} {} {}`", error, left, operator, right)
+}
+
+fn eval_relational(
+    left: &Expr,
+    operator: &RelOp,
+    right: &Expr,
+    env: &Env,
+) -> Result<EvalResult, DynError> {
+    let EvalResult {
+        result: left,
+        explanation: left_explanation,
+        ..
+    } = eval(left, env)?;
+
+    let EvalResult {
+        result: right,
+        explanation: right_explanation,
+        ..
+    } = eval(right, env)?;
+
+    // a pool is compared by its sum, so comparisons can be added up to count successes
+    let result = Value::Bool(rel_op_eval_value(
+        operator,
+        left.as_number()?,
+        right.as_number()?,
+    )?);
+
+    Ok(EvalResult {
+        result,
+        explanation: format!("{} {} {}", left_explanation, operator, right_explanation),
+        is_roll: false,
+    })
+}
+
+/// Keeps the dice of the pool, or the elements of the list, on the left which
+/// compare true against the right, like `8d6 = 6` inside `count`.
+fn eval_filter(
+    left: &Expr,
+    operator: &RelOp,
+    right: &Expr,
+    env: &Env,
+) -> Result<EvalResult, DynError> {
+    let EvalResult {
+        result: left,
+        explanation: left_explanation,
+        ..
+    } = eval(left, env)?;
+
+    let EvalResult {
+        result: right,
+        explanation: right_explanation,
+        ..
+    } = eval(right, env)?;
+
+    Ok(EvalResult {
+        result: left.filter(operator, right.as_number()?)?,
+        explanation: format!("{} {} {}", left_explanation, operator, right_explanation),
+        is_roll: false,
+    })
+}
+
+fn eval_where(expr: &Expr, condition: &Condition, env: &Env) -> Result<EvalResult, DynError> {
+    let EvalResult {
+        result,
+        explanation,
+        ..
+    } = eval(expr, env)?;
+
+    let EvalResult {
+        result: value,
+        explanation: value_explanation,
+        ..
+    } = eval(&condition.value, env)?;
+
+    Ok(EvalResult {
+        result: result.filter(&condition.operator, value.as_number()?)?,
+        explanation: format!(
+            "{} where {}{}",
+            explanation, condition.operator, value_explanation
+        ),
+        is_roll: false,
+    })
+}
+
+fn eval_logical(
+    left: EvalResult,
+    operator: &LogicOp,
+    right: &Expr,
+    env: &Env,
+) -> Result<EvalResult, DynError> {
+    let EvalResult {
+        result: left,
+        explanation: left_explanation,
+        ..
+    } = left;
+
+    let left = left.as_bool()?;
+
+    // the right side isn't evaluated when the left side decides the result,
+    // so it can guard a recursive call or a roll
+    let short_circuits = match operator {
+        LogicOp::And => !left,
+        LogicOp::Or => left,
+    };
+
+    if short_circuits {
+        return Ok(EvalResult {
+            result: Value::Bool(left),
+            explanation: left_explanation,
+            is_roll: false,
+        });
+    }
+
+    let EvalResult {
+        result: right,
+        explanation: right_explanation,
+        ..
+    } = eval(right, env)?;
+
+    Ok(EvalResult {
+        result: Value::Bool(right.as_bool()?),
+        explanation: format!("{} {} {}", left_explanation, operator, right_explanation),
+        is_roll: false,
+    })
+}
+
+fn eval_if(
+    condition: &Expr,
+    then: &Expr,
+    otherwise: &Expr,
+    env: &Env,
+) -> Result<EvalResult, DynError> {
+    let EvalResult {
+        result: condition,
+        explanation: condition_explanation,
+        ..
+    } = eval(condition, env)?;
+
+    // only the branch that was taken is evaluated, so only its dice are rolled
+    let (keyword, branch) = if condition.as_bool()? {
+        ("then", then)
+    } else {
+        ("else", otherwise)
+    };
+
+    let EvalResult {
+        result,
+        explanation,
+        ..
+    } = eval(branch, env)?;
+
+    Ok(EvalResult {
+        result,
+        explanation: format!("if {} {} {}", condition_explanation, keyword, explanation),
+        is_roll: false,
+    })
+}
+
+/// Looks up the entry of the table `name` for `roll`, and rolls the dice in it.
+/// Tables which aren't defined in the config are read from `name.tbl`.
+fn eval_table(name: &str, roll: Option<&Expr>, env: &Env) -> Result<EvalResult, DynError> {
+    let table = env.get_table(name)?;
+
+    let roll = match roll {
+        Some(roll) => eval(roll, env)?,
+        None => {
+            let (min, max) = table.bounds();
+            let die = Expr::Roll {
+                rolls: Box::new(Expr::Int(1)),
+                sides: Sides::Range {
+                    min: Box::new(Expr::Int(min)),
+                    max: Box::new(Expr::Int(max)),
+                },
+                modifiers: vec![],
+            };
+
+            eval(&die, env)?
+        }
+    };
+
+    let value = roll.result.as_int()?;
+    let entry = table
+        .lookup(value)
+        .ok_or_else(|| format!("Table '{}' has no entry for {}", name, value))?;
+
+    // entries can roll on tables themselves, even on the same one, so they count
+    // towards the recursion limit like calls do
+    let entry_env = env.enter(&format!("table({})", name), HashMap::new())?;
+
+    let mut text = String::new();
+    let mut explanation = String::new();
+
+    for part in entry {
+        match part {
+            table::Part::Text(part) => {
+                text.push_str(part);
+                explanation.push_str(part);
+            }
+            table::Part::Roll(expr) => {
+                let result = eval(expr, &entry_env)?;
+                text.push_str(&result.result.to_string());
+                explanation.push_str(&result.explanation);
+            }
+        }
+    }
+
+    Ok(EvalResult {
+        result: Value::Text(text),
+        explanation: format!("table({}, {}): {}", name, roll.explanation, explanation),
+        is_roll: false,
+    })
+}
+
+fn eval_repeat(count: &Expr, expr: &Expr, env: &Env) -> Result<EvalResult, DynError> {
+    let count = eval(count, env)?.result.as_int()?;
+
+    if count < 0 {
+        return Err("Cannot repeat a negative number of times".into());
+    }
+
+    if count as u64 > env.limits.dice {
+        return Err(format!(
+            "Cannot repeat more than the limit of {} times",
+            env.limits.dice
+        )
+        .into());
+    }
+
+    let mut results = vec![];
+    let mut explanations = vec![];
+
+    for _ in 0..count {
+        let EvalResult {
+            result,
+            explanation,
+            ..
+        } = eval(expr, env)?;
+
+        results.push(result);
+        explanations.push(explanation);
+    }
+
+    Ok(EvalResult {
+        result: Value::List(results),
+        explanation: format!("[{}]", explanations.join(", ")),
+        is_roll: false,
+    })
+}
+
+/// Rolls of more dice than this without modifiers are kept as a tally of their
+/// faces, without explaining every die or keeping it in memory.
+const PLAIN_DICE: i64 = 10_000;
+
 fn eval_roll(
     rolls: &Expr,
     sides: &Sides,
     modifiers: &[Modifier],
-    cli: &Cli,
+    env: &Env,
 ) -> Result<EvalResult, DynError> {
     let EvalResult {
         result,
         explanation: rolls_explanation,
         is_roll: rolls_explanation_is_roll,
-    } = eval(rolls, cli)?;
+    } = eval(rolls, env)?;
 
-    let rolls = result.round() as i64;
+    let rolls = result
+        .as_int()
+        .map_err(|e| format!("Invalid number of dice `{}`: {}", rolls_explanation, e))?;
 
     if rolls < 0 {
         return Err("Cannot roll a negative number of times".into());
     }
 
-    let (side_values, sides_explanation, sides_explanation_is_roll, is_fudge): (Vec<i64>, _, _, _) =
-        match sides {
-            Sides::Expr(expr) => {
-                let EvalResult {
-                    result,
-                    explanation,
-                    is_roll,
-                } = eval(expr, cli)?;
+    let (side_values, sides_explanation, sides_explanation_is_roll, is_fudge) =
+        eval_sides(sides, env)?;
 
-                (
-                    (1..result.round() as i64 + 1).collect(),
-                    explanation,
-                    is_roll,
-                    false,
-                )
-            }
-            Sides::Range { min, max } => {
-                let EvalResult {
-                    result: min,
-                    explanation: min_explanation,
-                    ..
-                } = eval(min, cli)?;
+    if side_values.is_empty() {
+        return Err(format!("Cannot roll a die without sides in `d{}`", sides).into());
+    }
 
-                let min = min.round() as i64;
+    // `d100` is rolled as a tens die and a units die, just like `d%`
+    let is_percentile =
+        side_values.is_percentile() && matches!(env.cli.mode, None | Some(cli::Mode::Rng));
 
-                let EvalResult {
-                    result: max,
-                    explanation: max_explanation,
-                    ..
-                } = eval(max, cli)?;
+    let plain = if modifiers.is_empty() && rolls > PLAIN_DICE {
+        roll_plain(&env.cli.mode, rolls, &side_values, env)?
+    } else {
+        None
+    };
 
-                let max = max.round() as i64;
+    let (result, results_explanation) = match plain {
+        Some(faces) => (Value::Tally(faces), format!("{} dice", rolls)),
+        None => {
+            let results = env.cli.mode.eval(rolls, &side_values, modifiers, env)?;
 
-                (
-                    (min..=max).collect(),
-                    format!("{}..{}", min_explanation, max_explanation),
-                    false,
-                    false,
-                )
-            }
-            Sides::Values(values) => {
-                let mut results = vec![];
+            let mut results_explanation = String::new();
 
-                for value in values {
-                    let EvalResult {
-                        result,
-                        explanation,
-                        ..
-                    } = eval(value, cli)?;
+            for (i, result) in results.iter().enumerate() {
+                let explanation = match is_percentile {
+                    true => result.explain_percentile(),
+                    false => result.explain(),
+                };
 
-                    results.push((result.round() as i64, explanation));
-                }
+                results_explanation.push_str(
+                    to_fudge(
+                        &explanation,
+                        is_fudge,
+                        env.cli.mode.as_ref().unwrap_or(&cli::Mode::Rng),
+                    )
+                    .as_str(),
+                );
 
-                (
-                    results.iter().map(|r| r.0).collect(),
-                    results
-                        .into_iter()
-                        .map(|r| r.1)
-                        .collect::<Vec<_>>()
-                        .join(", ")
-                        .to_string(),
-                    false,
-                    false,
-                )
+                if i < results.len() - 1 {
+                    results_explanation.push_str(", ");
+                }
             }
-            Sides::Fudge => ((-1..=1).collect(), "f".to_string(), false, true),
-        };
-
-    let results = cli.mode.eval(rolls, &side_values, modifiers, cli)?;
-
-    let mut results_explanation = String::new();
 
-    for (i, result) in results.iter().enumerate() {
-        results_explanation.push_str(
-            to_fudge(
-                &result.explain(),
-                is_fudge,
-                cli.mode.as_ref().unwrap_or(&cli::Mode::Rng),
-            )
-            .as_str(),
-        );
+            let pool = Value::Pool(
+                results
+                    .iter()
+                    .filter(|r| r.count_roll(env.cli.mode.as_ref()))
+                    .map(|r| Number::from_die(r.sum(env.cli.mode.as_ref())))
+                    .collect(),
+            );
 
-        if i < results.len() - 1 {
-            results_explanation.push_str(", ");
+            (pool, results_explanation)
         }
-    }
+    };
 
     let explanation = match (rolls_explanation_is_roll, sides_explanation_is_roll) {
         (true, true) => format!(
@@ -219,15 +626,312 @@ fn eval_roll(
     };
 
     Ok(EvalResult {
-        result: results
-            .iter()
-            .map(|r| r.sum(cli.mode.as_ref()))
-            .sum::<f64>(),
+        result,
         explanation,
         is_roll: true,
     })
 }
 
+/// Draws cards from the deck `d{sides}` without putting them back, so no card
+/// comes up twice until the deck is used up.
+fn eval_draw(count: &Expr, sides: &Sides, env: &Env) -> Result<EvalResult, DynError> {
+    let EvalResult {
+        result,
+        explanation: count_explanation,
+        ..
+    } = eval(count, env)?;
+
+    let count = result
+        .as_int()
+        .map_err(|e| format!("Invalid number of cards `{}`: {}", count_explanation, e))?;
+
+    if count < 0 {
+        return Err("Cannot draw a negative number of cards".into());
+    }
+
+    let (cards, ..) = eval_sides(sides, env)?;
+
+    if count as i128 > cards.len() {
+        return Err(format!(
+            "Cannot draw {} cards from `d{}`, which has {}",
+            count,
+            sides,
+            cards.len()
+        )
+        .into());
+    }
+
+    env.count_dice(count as u64)?;
+
+    let count = count as usize;
+
+    // every card is as likely to be drawn as with a die, so only the extremes
+    // differ from rolling, where every card can only be drawn once
+    let hand = match &env.cli.mode {
+        None | Some(cli::Mode::Rng) => env
+            .decks
+            .draw(
+                &format!("d{}", sides),
+                &cards,
+                count,
+                env,
+                &mut rand::thread_rng(),
+            )?
+            .into_iter()
+            .map(|card| card as f64)
+            .collect(),
+        Some(cli::Mode::Avg | cli::Mode::Simavg(_)) => vec![cards.avg(); count],
+        Some(cli::Mode::Med) => vec![cards.med(); count],
+        Some(cli::Mode::Min) => (0..count)
+            .filter_map(|i| cards.nth_lowest(i))
+            .map(|card| card as f64)
+            .collect(),
+        Some(cli::Mode::Max) => (0..count)
+            .filter_map(|i| cards.nth_lowest(cards.len() as usize - 1 - i))
+            .map(|card| card as f64)
+            .collect::<Vec<_>>(),
+    };
+
+    Ok(EvalResult {
+        result: Value::Pool(hand.iter().map(|card| Number::from_die(*card)).collect()),
+        explanation: format!(
+            "[{}]",
+            hand.iter()
+                .map(|card| card.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        is_roll: true,
+    })
+}
+
+/// Evaluates the sides of a die, returning them with their explanation, whether
+/// that explanation is a roll, and whether the die is a fudge die.
+fn eval_sides(sides: &Sides, env: &Env) -> Result<(SideValues, String, bool, bool), DynError> {
+    Ok(match sides {
+        Sides::Expr(expr) => {
+            let EvalResult {
+                result,
+                explanation,
+                is_roll,
+            } = eval(expr, env)?;
+
+            let sides = result
+                .as_int()
+                .map_err(|e| invalid_sides(e, &explanation))?;
+
+            check_sides(sides as i128, env)?;
+
+            (
+                SideValues::Range { min: 1, max: sides },
+                explanation,
+                is_roll,
+                false,
+            )
+        }
+        Sides::Range { min, max } => {
+            let EvalResult {
+                result: min,
+                explanation: min_explanation,
+                ..
+            } = eval(min, env)?;
+
+            let min = min
+                .as_int()
+                .map_err(|e| invalid_sides(e, &min_explanation))?;
+
+            let EvalResult {
+                result: max,
+                explanation: max_explanation,
+                ..
+            } = eval(max, env)?;
+
+            let max = max
+                .as_int()
+                .map_err(|e| invalid_sides(e, &max_explanation))?;
+
+            check_sides(max as i128 - min as i128 + 1, env)?;
+
+            (
+                SideValues::Range { min, max },
+                format!("{}..{}", min_explanation, max_explanation),
+                false,
+                false,
+            )
+        }
+        Sides::Values(values) => {
+            let mut results = vec![];
+
+            for value in values {
+                let EvalResult {
+                    result,
+                    explanation,
+                    ..
+                } = eval(value, env)?;
+
+                let side = result
+                    .as_int()
+                    .map_err(|e| invalid_sides(e, &explanation))?;
+
+                results.push((side, explanation));
+            }
+
+            (
+                SideValues::Values(results.iter().map(|r| r.0).collect()),
+                results
+                    .into_iter()
+                    .map(|r| r.1)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+                    .to_string(),
+                false,
+                false,
+            )
+        }
+        Sides::Weighted(weights) => {
+            let mut sides = vec![];
+            let mut explanations = vec![];
+
+            for (value, weight) in weights {
+                let value = eval(value, env)?;
+                let side = value
+                    .result
+                    .as_int()
+                    .map_err(|e| invalid_sides(e, &value.explanation))?;
+
+                let weight = eval(weight, env)?;
+                let count = weight
+                    .result
+                    .as_int()
+                    .map_err(|e| invalid_sides(e, &weight.explanation))?;
+
+                if count < 0 {
+                    return Err(format!("Cannot use negative weight {}", count).into());
+                }
+
+                sides.push((side, count as u64));
+                explanations.push(format!("{}: {}", value.explanation, weight.explanation));
+            }
+
+            (
+                SideValues::Weighted(sides),
+                explanations.join(", "),
+                false,
+                false,
+            )
+        }
+        Sides::Fudge => (
+            SideValues::Range { min: -1, max: 1 },
+            "f".to_string(),
+            false,
+            true,
+        ),
+        Sides::Percentile => (
+            SideValues::Range {
+                min: percentile::SIDES.0,
+                max: percentile::SIDES.1,
+            },
+            "%".to_string(),
+            false,
+            false,
+        ),
+    })
+}
+
+/// Evaluates a group like `{1d20 + 5, 1d20 + 5}kh1` into a pool of the results
+/// which were kept, in the order they were written.
+fn eval_group(exprs: &[Expr], modifiers: &[Modifier], env: &Env) -> Result<EvalResult, DynError> {
+    let mut results = vec![];
+
+    for expr in exprs {
+        let EvalResult {
+            result,
+            explanation,
+            ..
+        } = eval(expr, env)?;
+
+        results.push((result.as_number()?, explanation));
+    }
+
+    let mut kept = vec![true; results.len()];
+
+    for modifier in modifiers {
+        let (amount, verb) = match modifier {
+            Modifier::KeepHighest(amount) | Modifier::KeepLowest(amount) => (amount, "keep"),
+            Modifier::DropHighest(amount) | Modifier::DropLowest(amount) => (amount, "drop"),
+            other => unreachable!("{other:?} is not a group modifier"),
+        };
+
+        let amount = eval(amount, env)?.result.as_int()?;
+
+        // the results which are still kept, lowest first
+        let mut candidates = (0..results.len()).filter(|i| kept[*i]).collect::<Vec<_>>();
+        candidates.sort_by(|a, b| results[*a].0.total_cmp(&results[*b].0));
+
+        if amount < 0 {
+            return Err(format!("Cannot {} a negative number of results", verb).into());
+        }
+
+        if amount as usize > candidates.len() {
+            return Err(format!("Cannot {} more results than in the group", verb).into());
+        }
+
+        let amount = amount as usize;
+        let len = candidates.len();
+
+        let dropped = match modifier {
+            Modifier::KeepHighest(_) => &candidates[..len - amount],
+            Modifier::KeepLowest(_) => &candidates[amount..],
+            Modifier::DropHighest(_) => &candidates[len - amount..],
+            _ => &candidates[..amount],
+        };
+
+        for i in dropped {
+            kept[*i] = false;
+        }
+    }
+
+    let explanations = results
+        .iter()
+        .zip(&kept)
+        .map(|((_, explanation), kept)| match kept {
+            true => explanation.clone(),
+            false if explanation.contains(' ') => format!("({})d", explanation),
+            false => format!("{}d", explanation),
+        })
+        .collect::<Vec<_>>();
+
+    Ok(EvalResult {
+        result: Value::Pool(
+            results
+                .iter()
+                .zip(&kept)
+                .filter(|(_, kept)| **kept)
+                .map(|((result, _), _)| *result)
+                .collect(),
+        ),
+        explanation: format!("{{{}}}", explanations.join(", ")),
+        is_roll: false,
+    })
+}
+
+/// Stops dice with too many sides before they are collected into a list of sides.
+fn check_sides(sides: i128, env: &Env) -> Result<(), DynError> {
+    if sides > env.limits.sides as i128 {
+        return Err(format!(
+            "Cannot roll a die with {} sides, the limit is {}",
+            sides, env.limits.sides
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+fn invalid_sides(error: DynError, explanation: &str) -> String {
+    format!("Invalid side `{}`: {}", explanation, error)
+}
+
 fn to_fudge(roll_str: &str, is_fudge: bool, mode: &cli::Mode) -> String {
     if !is_fudge || !matches!(mode, cli::Mode::Rng) {
         return roll_str.to_string();
@@ -243,7 +947,7 @@ fn to_fudge(roll_str: &str, is_fudge: bool, mode: &cli::Mode) -> String {
 
 #[derive(Debug, Clone)]
 pub struct EvalResult {
-    pub result: f64,
+    pub result: Value,
     pub explanation: String,
     pub is_roll: bool,
 }
@@ -270,35 +974,54 @@ impl DiceRoll {
         matches!(
             (mode.unwrap_or(&cli::Mode::Rng), &self.modification),
             (cli::Mode::Avg, Some(Modification::Rerolled))
-                | (_, None | Some(Modification::Exploded))
+                | (
+                    _,
+                    None | Some(
+                        Modification::Exploded
+                            | Modification::Compounded
+                            | Modification::Capped
+                            | Modification::Clamped(_)
+                    )
+                )
         )
     }
 
     fn explain(&self) -> String {
-        format!(
-            "{}{}",
-            self.value,
-            self.modification
-                .as_ref()
-                .map(|m| m.suffix())
-                .unwrap_or("".to_owned())
-        )
+        match &self.modification {
+            Some(Modification::Clamped(from)) => format!("{}→{}", from, self.value),
+            modification => format!(
+                "{}{}",
+                self.value,
+                modification
+                    .as_ref()
+                    .map(|m| m.suffix())
+                    .unwrap_or("".to_owned())
+            ),
+        }
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct DiceRolls {
     pub values: Vec<DiceRoll>,
-    sides: Vec<i64>,
+    min_side: i64,
+    max_side: i64,
     modification: Option<Modification>,
+    critical: Option<Critical>,
+    /// The tens dice of a percentile die which weren't used, because of bonus or penalty dice.
+    unused_tens: Vec<i64>,
 }
 
 impl DiceRolls {
-    fn new(value: f64, sides: Vec<i64>) -> Self {
+    /// `bounds` are the lowest and highest side of the die.
+    fn new(value: f64, (min_side, max_side): (i64, i64)) -> Self {
         Self {
             values: vec![DiceRoll::new(value)],
-            sides,
+            min_side,
+            max_side,
             modification: None,
+            critical: None,
+            unused_tens: vec![],
         }
     }
 
@@ -314,6 +1037,36 @@ impl DiceRolls {
         self.modification = Some(Modification::Dropped)
     }
 
+    fn mark_critical(&mut self, critical: Critical) {
+        self.critical = Some(critical);
+    }
+
+    /// Replaces the last value, like the result of a percentile die after its bonus dice.
+    fn set_last(&mut self, value: f64) {
+        if let Some(last) = self.values.last_mut() {
+            last.value = value;
+        }
+    }
+
+    /// Replaces the last value with `value`, remembering what was rolled.
+    fn clamp_last(&mut self, value: f64) {
+        let Some(last) = self.values.last_mut() else {
+            return;
+        };
+
+        let rolled = match last.modification {
+            Some(Modification::Clamped(rolled)) => rolled,
+            _ => last.value,
+        };
+
+        last.value = value;
+        last.modification = (rolled != value).then_some(Modification::Clamped(rolled));
+    }
+
+    fn add_unused_tens(&mut self, tens: i64) {
+        self.unused_tens.push(tens);
+    }
+
     fn reroll(&mut self, new_roll: f64) {
         if let Some(last) = self.values.iter_mut().last() {
             last.modify(Modification::Rerolled);
@@ -330,8 +1083,39 @@ impl DiceRolls {
         self.add_value(new_roll);
     }
 
+    /// Adds the explosions of the value at `start` into that value, so `1d6!!`
+    /// shows a single `14!!` instead of `{6!, 6!, 2}`.
+    fn compound(&mut self, start: usize) {
+        if self.values.len() <= start + 1 {
+            return;
+        }
+
+        let capped = self
+            .values
+            .last()
+            .and_then(|last| last.modification.as_ref())
+            == Some(&Modification::Capped);
+
+        let value = self.values.drain(start..).map(|v| v.value).sum();
+        let mut compounded = DiceRoll::new(value);
+
+        compounded.modify(match capped {
+            true => Modification::Capped,
+            false => Modification::Compounded,
+        });
+
+        self.values.push(compounded);
+    }
+
+    /// Marks the last value as the end of a chain of explosions which was cut off.
+    fn cap(&mut self) {
+        if let Some(last) = self.values.iter_mut().last() {
+            last.modify(Modification::Capped);
+        }
+    }
+
     fn min_side(&self) -> i64 {
-        *self.sides.iter().min().expect("No sides")
+        self.min_side
     }
 
     // fn min_value(&self) -> i64 {
@@ -340,7 +1124,7 @@ impl DiceRolls {
     // }
 
     fn max_side(&self) -> i64 {
-        *self.sides.iter().max().expect("No sides")
+        self.max_side
     }
 
     // fn max_value(&self) -> i64 {
@@ -358,26 +1142,66 @@ impl DiceRolls {
 
     fn sum(&self, mode: Option<&cli::Mode>) -> f64 {
         if self.count_roll(mode) {
-            self.values
-                .iter()
-                .filter(|v| v.count_roll(mode))
-                .map(|v| v.value)
-                .sum()
+            self.rolled(mode)
         } else {
             0.0
         }
     }
 
+    /// The value the die shows, even if it was dropped.
+    fn rolled(&self, mode: Option<&cli::Mode>) -> f64 {
+        self.values
+            .iter()
+            .filter(|v| v.count_roll(mode))
+            .map(|v| v.value)
+            .sum()
+    }
+
     fn last(&self) -> f64 {
         self.values.last().expect("No values").value
     }
 
+    fn suffix(&self) -> String {
+        format!(
+            "{}{}",
+            self.critical.as_ref().map_or("", |c| c.suffix()),
+            self.modification
+                .as_ref()
+                .map(|m| m.suffix())
+                .unwrap_or("".to_owned())
+        )
+    }
+
+    /// Explains a percentile die as its tens die and units die, like `70 + 3`,
+    /// or `{30d, 70} + 3` when a penalty die replaced the tens die.
+    fn explain_percentile(&self) -> String {
+        if self.values.len() > 1 || self.values[0].modification.is_some() {
+            return self.explain();
+        }
+
+        let (tens, units) = percentile::split(self.values[0].value as i64);
+
+        let tens = match self.unused_tens.is_empty() {
+            true => format!("{:02}", tens),
+            false => format!(
+                "{{{}, {:02}}}",
+                self.unused_tens
+                    .iter()
+                    .map(|t| format!("{:02}d", t))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                tens
+            ),
+        };
+
+        match self.suffix().as_str() {
+            "" => format!("{} + {}", tens, units),
+            suffix => format!("({} + {}){}", tens, units, suffix),
+        }
+    }
+
     fn explain(&self) -> String {
-        let modified_text = self
-            .modification
-            .as_ref()
-            .map(|m| m.suffix())
-            .unwrap_or("".to_owned());
+        let modified_text = self.suffix();
 
         if self.values.len() == 1 {
             format!("{}{}", self.values[0].explain(), modified_text)
@@ -400,6 +1224,12 @@ pub enum Modification {
     Dropped,
     Rerolled,
     Exploded,
+    /// The sum of a die and its explosions, from a compounding `!!`.
+    Compounded,
+    /// Would have exploded again, but the chain of explosions was cut off.
+    Capped,
+    /// Raised or lowered by `min` or `max`, from the value which was rolled.
+    Clamped(f64),
 }
 
 impl Modification {
@@ -408,6 +1238,172 @@ impl Modification {
             Modification::Dropped => "d".to_owned(),
             Modification::Rerolled => "r".to_owned(),
             Modification::Exploded => "!".to_owned(),
+            Modification::Compounded => "!!".to_owned(),
+            Modification::Capped => "!…".to_owned(),
+            Modification::Clamped(_) => "".to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Critical {
+    Success,
+    Failure,
+}
+
+impl Critical {
+    fn suffix(&self) -> &'static str {
+        match self {
+            Critical::Success => "cs",
+            Critical::Failure => "cf",
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::{
+        analyzer::analyze,
+        cli::Cli,
+        config::Limits,
+        lexer::tokenize,
+        parser::{parse, Cursor},
+    };
+
+    /// Evaluates `source` with the command line arguments `args`, like `["-m", "max"]`.
+    fn eval_result(source: &str, args: &[&str]) -> EvalResult {
+        let cli = Cli::parse_from(["roll"].iter().chain(args));
+        let tree = analyze(parse(&mut Cursor::new(tokenize(source).unwrap())).unwrap()).unwrap();
+        let (definitions, limits, decks) =
+            (Definitions::default(), Limits::default(), Decks::default());
+
+        eval(&tree, &Env::new(&cli, &definitions, &limits, &decks)).unwrap()
+    }
+
+    fn eval_str(source: &str, args: &[&str]) -> Value {
+        eval_result(source, args).result
+    }
+
+    #[test]
+    fn test_comparisons_are_booleans() {
+        assert!(matches!(eval_str("1d20 + 5 >= 15", &[]), Value::Bool(_)));
+        assert!(matches!(eval_str("8d6 > 3", &[]), Value::Bool(_)));
+        assert_eq!(
+            eval_str("1d20 + 5 >= 15", &["-m", "max"]),
+            Value::Bool(true)
+        );
+        assert_eq!(
+            eval_str("(1d20 >= 11) + (1d20 >= 11)", &["-m", "max"])
+                .as_number()
+                .unwrap(),
+            Number::Int(2)
+        );
+        assert_eq!(
+            eval_str("(1d20 >= 11) + (1d20 >= 11)", &["-m", "min"])
+                .as_number()
+                .unwrap(),
+            Number::Int(0)
+        );
+    }
+
+    #[test]
+    fn test_big_rolls_are_pools() {
+        for rolls in [10_000, 10_001] {
+            let roll = format!("{}d6", rolls);
+            let sixes = format!("count({}d6 = 6)", rolls);
+
+            assert_eq!(
+                eval_str(&format!("count({})", roll), &[]),
+                Value::Number(Number::Int(rolls))
+            );
+            assert!(eval_str(&roll, &[]).as_int().unwrap() >= rolls);
+            assert_eq!(
+                eval_str(&sixes, &["-m", "max"]),
+                Value::Number(Number::Int(rolls))
+            );
+        }
+    }
+
+    #[test]
+    fn test_count_filters_pools() {
+        assert_eq!(
+            eval_str("count(8d6 = 6)", &["-m", "max"]),
+            Value::Number(Number::Int(8))
+        );
+        assert_eq!(
+            eval_str("count(8d6 < 6)", &["-m", "max"]),
+            Value::Number(Number::Int(0))
+        );
+    }
+
+    #[test]
+    fn test_functions_sum_pools_in_lists() {
+        let max = |source| eval_str(source, &["-m", "max"]);
+
+        assert_eq!(max("max(6x(3d6))"), Value::Number(Number::Int(18)));
+        assert_eq!(max("sum(6x(3d6))"), Value::Number(Number::Int(108)));
+        assert_eq!(max("max(3d6)"), Value::Number(Number::Int(6)));
+        assert_eq!(max("sum(3d6, 2d6)"), Value::Number(Number::Int(30)));
+
+        let Value::List(totals) = eval_str("sort(6x(3d6))", &[]) else {
+            panic!("sort should give a list");
+        };
+        let totals = totals
+            .iter()
+            .map(|total| total.as_int().unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(totals.len(), 6);
+        assert!(totals.iter().all(|total| (3..=18).contains(total)));
+        assert!(totals.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn test_self_referencing_table() {
+        let cli = Cli::parse_from(["roll"]);
+        let (mut definitions, limits, decks) =
+            (Definitions::default(), Limits::default(), Decks::default());
+        definitions.define_table(
+            "self".to_string(),
+            Table::parse("1-6: table(self)").unwrap(),
+        );
+
+        let tree = analyze(parse(&mut Cursor::new(tokenize("table(self)").unwrap())).unwrap());
+        let result = eval(
+            &tree.unwrap(),
+            &Env::new(&cli, &definitions, &limits, &decks),
+        );
+
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Maximum recursion depth"));
+    }
+
+    #[test]
+    fn test_max_explosions_are_capped() {
+        let depth = Limits::default().depth as i64;
+
+        assert_eq!(
+            eval_str("1d6!", &["-m", "max"]).as_number().unwrap(),
+            Number::Int(6 * (depth + 1))
+        );
+        assert_eq!(
+            eval_str("1d6!3", &["-m", "max"]).as_number().unwrap(),
+            Number::Int(24)
+        );
+    }
+
+    #[test]
+    fn test_compounding_explosions() {
+        let exploded = eval_result("2d6!3", &["-m", "max"]);
+        let compounded = eval_result("2d6!!3", &["-m", "max"]);
+
+        assert_eq!(exploded.result, compounded.result);
+        assert_eq!(exploded.explanation, "[{6!, 6!, 6!, 6}, {6!, 6!, 6!, 6}]");
+        assert_eq!(compounded.explanation, "[24!!, 24!!]");
+    }
+}
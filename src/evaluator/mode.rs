@@ -1,16 +1,25 @@
-use rand::Rng;
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 
 use crate::{
-    cli::{self, Cli},
+    cli,
     parser::{Condition, Modifier, RelOp},
     program::DynError,
 };
 
-use super::{eval, DiceRolls, EvalResult};
+use std::collections::{BTreeMap, HashSet};
+
+use rand::Rng;
+
+use super::{eval, percentile, Critical, DiceRolls, Env, EvalResult, Number, SideValues};
 
 type Roller<'a> = Box<
-    dyn Fn(i64, &[i64], &[Modifier], &Option<cli::Mode>, &Cli) -> Result<Vec<DiceRolls>, DynError>
+    dyn Fn(
+            i64,
+            &SideValues,
+            &[Modifier],
+            &Option<cli::Mode>,
+            &Env,
+        ) -> Result<Vec<DiceRolls>, DynError>
         + 'a,
 >;
 
@@ -18,9 +27,9 @@ pub trait Mode {
     fn eval(
         &self,
         rolls: i64,
-        side_values: &[i64],
+        side_values: &SideValues,
         modifiers: &[Modifier],
-        cli: &Cli,
+        env: &Env,
     ) -> Result<Vec<DiceRolls>, DynError>;
 }
 
@@ -28,38 +37,38 @@ impl Mode for Option<cli::Mode> {
     fn eval(
         &self,
         rolls: i64,
-        side_values: &[i64],
+        side_values: &SideValues,
         modifiers: &[Modifier],
-        cli: &Cli,
+        env: &Env,
     ) -> Result<Vec<DiceRolls>, DynError> {
+        // the simulations of simavg count their own dice
+        if !matches!(self, Some(cli::Mode::Simavg(_))) {
+            env.count_dice(rolls as u64)?;
+        }
+
         let roller = get_roller(self);
-        let results = roller(rolls, side_values, modifiers, self, cli)?;
+        let results = roller(rolls, side_values, modifiers, self, env)?;
         Ok(results)
     }
 }
 
-fn get_roller(mode: &Option<cli::Mode>) -> Roller {
+fn get_roller(mode: &Option<cli::Mode>) -> Roller<'_> {
     match mode {
         None | Some(cli::Mode::Rng) => Box::new(
             |rolls: i64,
-             side_values: &[i64],
+             side_values: &SideValues,
              modifiers: &[Modifier],
              mode: &Option<cli::Mode>,
-             cli: &Cli| {
+             env: &Env| {
                 let mut rng = rand::thread_rng();
                 let mut results = vec![];
 
-                for _ in 0..rolls {
-                    let len = side_values.len();
-                    if len == 0 {
-                        continue;
-                    }
+                if let Some(bounds) = side_values.bounds() {
+                    let sampler = side_values.sampler();
 
-                    let index = rng.gen_range(0..len);
-                    results.push(DiceRolls::new(
-                        side_values[index] as f64,
-                        side_values.to_vec(),
-                    ));
+                    for _ in 0..rolls {
+                        results.push(DiceRolls::new(sampler.sample(&mut rng) as f64, bounds));
+                    }
                 }
 
                 apply_modifiers(
@@ -69,7 +78,7 @@ fn get_roller(mode: &Option<cli::Mode>) -> Roller {
                     modifiers,
                     &mut results,
                     mode,
-                    cli,
+                    env,
                 )?;
 
                 Ok(results)
@@ -77,22 +86,14 @@ fn get_roller(mode: &Option<cli::Mode>) -> Roller {
         ),
         Some(cli::Mode::Avg) => Box::new(
             |rolls: i64,
-             side_values: &[i64],
+             side_values: &SideValues,
              modifiers: &[Modifier],
              mode: &Option<cli::Mode>,
-             cli: &Cli| {
+             env: &Env| {
                 let mut results = vec![];
 
-                for _ in 0..rolls {
-                    let len = side_values.len();
-                    if len == 0 {
-                        continue;
-                    }
-
-                    results.push(DiceRolls::new(
-                        avg(&side_values.iter().map(|v| *v as f64).collect::<Vec<_>>()),
-                        side_values.to_vec(),
-                    ));
+                if let Some(bounds) = side_values.bounds() {
+                    results = vec![DiceRolls::new(side_values.avg(), bounds); rolls as usize];
                 }
 
                 apply_modifiers(
@@ -102,7 +103,7 @@ fn get_roller(mode: &Option<cli::Mode>) -> Roller {
                     modifiers,
                     &mut results,
                     mode,
-                    cli,
+                    env,
                 )?;
 
                 Ok(results)
@@ -110,22 +111,14 @@ fn get_roller(mode: &Option<cli::Mode>) -> Roller {
         ),
         Some(cli::Mode::Min) => Box::new(
             |rolls: i64,
-             side_values: &[i64],
+             side_values: &SideValues,
              modifiers: &[Modifier],
              mode: &Option<cli::Mode>,
-             cli: &Cli| {
+             env: &Env| {
                 let mut results = vec![];
 
-                for _ in 0..rolls {
-                    let len = side_values.len();
-                    if len == 0 {
-                        continue;
-                    }
-
-                    results.push(DiceRolls::new(
-                        *side_values.iter().min().expect("No sides") as f64,
-                        side_values.to_vec(),
-                    ));
+                if let Some(bounds) = side_values.bounds() {
+                    results = vec![DiceRolls::new(bounds.0 as f64, bounds); rolls as usize];
                 }
 
                 apply_modifiers(
@@ -135,7 +128,7 @@ fn get_roller(mode: &Option<cli::Mode>) -> Roller {
                     modifiers,
                     &mut results,
                     mode,
-                    cli,
+                    env,
                 )?;
 
                 Ok(results)
@@ -143,22 +136,14 @@ fn get_roller(mode: &Option<cli::Mode>) -> Roller {
         ),
         Some(cli::Mode::Max) => Box::new(
             |rolls: i64,
-             side_values: &[i64],
+             side_values: &SideValues,
              modifiers: &[Modifier],
              mode: &Option<cli::Mode>,
-             cli: &Cli| {
+             env: &Env| {
                 let mut results = vec![];
 
-                for _ in 0..rolls {
-                    let len = side_values.len();
-                    if len == 0 {
-                        continue;
-                    }
-
-                    results.push(DiceRolls::new(
-                        *side_values.iter().max().expect("No sides") as f64,
-                        side_values.to_vec(),
-                    ));
+                if let Some(bounds) = side_values.bounds() {
+                    results = vec![DiceRolls::new(bounds.1 as f64, bounds); rolls as usize];
                 }
 
                 apply_modifiers(
@@ -168,7 +153,7 @@ fn get_roller(mode: &Option<cli::Mode>) -> Roller {
                     modifiers,
                     &mut results,
                     mode,
-                    cli,
+                    env,
                 )?;
 
                 Ok(results)
@@ -176,19 +161,14 @@ fn get_roller(mode: &Option<cli::Mode>) -> Roller {
         ),
         Some(cli::Mode::Med) => Box::new(
             |rolls: i64,
-             side_values: &[i64],
+             side_values: &SideValues,
              modifiers: &[Modifier],
              mode: &Option<cli::Mode>,
-             cli: &Cli| {
+             env: &Env| {
                 let mut results = vec![];
 
-                for _ in 0..rolls {
-                    let len = side_values.len();
-                    if len == 0 {
-                        continue;
-                    }
-
-                    results.push(DiceRolls::new(med(side_values), side_values.to_vec()));
+                if let Some(bounds) = side_values.bounds() {
+                    results = vec![DiceRolls::new(side_values.med(), bounds); rolls as usize];
                 }
 
                 apply_modifiers(
@@ -198,7 +178,7 @@ fn get_roller(mode: &Option<cli::Mode>) -> Roller {
                     modifiers,
                     &mut results,
                     mode,
-                    cli,
+                    env,
                 )?;
 
                 Ok(results)
@@ -206,17 +186,17 @@ fn get_roller(mode: &Option<cli::Mode>) -> Roller {
         ),
         Some(cli::Mode::Simavg(v)) => Box::new(
             move |rolls: i64,
-                  side_values: &[i64],
+                  side_values: &SideValues,
                   modifiers: &[Modifier],
                   _mode: &Option<cli::Mode>,
-                  cli: &Cli| {
+                  env: &Env| {
                 let evals = (0..*v)
                     .into_par_iter()
                     .map(|_| -> Result<_, DynError> {
                         Ok(Some(cli::Mode::Rng)
-                            .eval(rolls, side_values, modifiers, cli)?
+                            .eval(rolls, side_values, modifiers, env)?
                             .iter()
-                            .map(|v| v.sum(cli.mode.as_ref()))
+                            .map(|v| v.sum(env.cli.mode.as_ref()))
                             .collect::<Vec<_>>())
                     })
                     .collect::<Result<Vec<_>, _>>()?;
@@ -226,148 +206,224 @@ fn get_roller(mode: &Option<cli::Mode>) -> Roller {
                         .iter()
                         .map(|rolls| rolls.iter().sum::<f64>())
                         .collect::<Vec<_>>()),
-                    side_values.to_vec(),
+                    side_values.bounds().unwrap_or_default(),
                 )])
             },
         ),
     }
 }
 
+/// Rolls `rolls` plain dice, only counting how many of them show each face,
+/// without keeping track of rerolls or explosions, which a roll without
+/// modifiers doesn't have. Returns `None` if the mode has to roll every die
+/// the usual way.
+pub fn roll_plain(
+    mode: &Option<cli::Mode>,
+    rolls: i64,
+    side_values: &SideValues,
+    env: &Env,
+) -> Result<Option<Vec<(Number, u64)>>, DynError> {
+    // how many dice are rolled between checks of the time limit
+    const CHECK_EVERY: i64 = 1 << 16;
+
+    let Some((min, max)) = side_values.bounds() else {
+        return Ok(Some(vec![]));
+    };
+
+    let value = match mode {
+        None | Some(cli::Mode::Rng) => {
+            env.count_dice(rolls as u64)?;
+
+            let mut rng = rand::thread_rng();
+            let mut faces = BTreeMap::new();
+            let sampler = side_values.sampler();
+
+            for i in 0..rolls {
+                if i % CHECK_EVERY == 0 {
+                    env.check_time()?;
+                }
+
+                *faces.entry(sampler.sample(&mut rng)).or_insert(0) += 1;
+            }
+
+            return Ok(Some(
+                faces
+                    .into_iter()
+                    .map(|(face, count)| (Number::Int(face), count))
+                    .collect(),
+            ));
+        }
+        Some(cli::Mode::Avg) => side_values.avg(),
+        Some(cli::Mode::Min) => min as f64,
+        Some(cli::Mode::Max) => max as f64,
+        Some(cli::Mode::Med) => side_values.med(),
+        Some(cli::Mode::Simavg(_)) => return Ok(None),
+    };
+
+    env.count_dice(rolls as u64)?;
+    Ok(Some(vec![(Number::from_die(value), rolls as u64)]))
+}
+
 fn apply_modifiers(
     rolls: i64,
-    side_values: &[i64],
+    side_values: &SideValues,
     roller: Roller,
     modifiers: &[Modifier],
     results: &mut [DiceRolls],
     mode: &Option<cli::Mode>,
-    cli: &Cli,
+    env: &Env,
 ) -> Result<(), DynError> {
+    // the bounds of every `min` and `max` so far, which the avg and med modes
+    // need to work out the distribution of the clamped die
+    let mut clamp = (f64::NEG_INFINITY, f64::INFINITY);
+
     for modifier in modifiers {
         match modifier {
-            Modifier::KeepHighest(expr) => {
-                let EvalResult { result, .. } = eval(expr, cli)?;
+            Modifier::KeepHighest(expr)
+            | Modifier::KeepLowest(expr)
+            | Modifier::DropHighest(expr)
+            | Modifier::DropLowest(expr) => {
+                let EvalResult { result, .. } = eval(expr, env)?;
 
-                let value = result.round() as i64;
+                let value = result.as_int()?;
+
+                let verb = match modifier {
+                    Modifier::KeepHighest(_) | Modifier::KeepLowest(_) => "keep",
+                    _ => "drop",
+                };
 
                 if value < 0 {
-                    return Err("Cannot keep a negative number of dice".into());
+                    return Err(format!("Cannot {} a negative number of dice", verb).into());
                 }
 
                 if value > rolls {
-                    return Err("Cannot keep more dice than rolled".into());
+                    return Err(format!("Cannot {} more dice than rolled", verb).into());
                 }
 
-                results.sort_by(|a, b| {
-                    a.sum(cli.mode.as_ref())
-                        .partial_cmp(&b.sum(cli.mode.as_ref()))
+                // the dice are dropped where they are, so they stay in the order they were rolled
+                let mut order = (0..results.len()).collect::<Vec<_>>();
+                order.sort_by(|a, b| {
+                    results[*a]
+                        .sum(env.cli.mode.as_ref())
+                        .partial_cmp(&results[*b].sum(env.cli.mode.as_ref()))
                         .expect("Cannot compare")
                 });
 
-                for i in 0..(results.len() - value as usize) {
-                    results[i].drop();
-                }
-
-                results.reverse();
-            }
-            Modifier::KeepLowest(expr) => {
-                let EvalResult { result, .. } = eval(expr, cli)?;
-
-                let value = result.round() as i64;
-
-                if value < 0 {
-                    return Err("Cannot keep a negative number of dice".into());
-                }
+                let value = value as usize;
+                let len = order.len();
 
-                if value > rolls {
-                    return Err("Cannot keep more dice than rolled".into());
-                }
-
-                results.sort_by(|a, b| {
-                    b.sum(cli.mode.as_ref())
-                        .partial_cmp(&a.sum(cli.mode.as_ref()))
-                        .expect("Cannot compare")
-                });
+                let dropped = match modifier {
+                    Modifier::KeepHighest(_) => &order[..len - value],
+                    Modifier::KeepLowest(_) => &order[value..],
+                    Modifier::DropHighest(_) => &order[len - value..],
+                    _ => &order[..value],
+                };
 
-                for i in 0..(results.len() - value as usize) {
-                    results[i].drop()
+                for i in dropped {
+                    results[*i].drop();
                 }
             }
-            Modifier::DropHighest(expr) => {
-                let EvalResult { result, .. } = eval(expr, cli)?;
-
-                let value = result.round() as i64;
-
-                if value < 0 {
-                    return Err("Cannot drop a negative number of dice".into());
+            Modifier::Unique => {
+                let kept = results
+                    .iter()
+                    .filter(|r| r.count_roll(env.cli.mode.as_ref()))
+                    .count();
+
+                if kept as i128 > side_values.distinct() {
+                    return Err(format!(
+                        "Cannot make {} dice unique with {} different sides",
+                        kept,
+                        side_values.distinct()
+                    )
+                    .into());
                 }
 
-                if value > rolls {
-                    return Err("Cannot drop more dice than rolled".into());
-                }
+                let mut rng = rand::thread_rng();
+                let sampler = side_values.sampler();
+                let mut seen = HashSet::new();
 
-                results.sort_by(|a, b| {
-                    b.sum(cli.mode.as_ref())
-                        .partial_cmp(&a.sum(cli.mode.as_ref()))
-                        .expect("Cannot compare")
-                });
+                let kept = results
+                    .iter_mut()
+                    .filter(|r| r.count_roll(env.cli.mode.as_ref()));
 
-                (0..value as usize).for_each(|i| results[i].drop());
-            }
-            Modifier::DropLowest(expr) => {
-                let EvalResult { result, .. } = eval(expr, cli)?;
+                for (i, result) in kept.enumerate() {
+                    env.check_time()?;
 
-                let value = result.round() as i64;
+                    match mode {
+                        // every die is still as likely to show any side, so only the
+                        // extremes change, where every die needs a different side
+                        Some(cli::Mode::Min) => {
+                            result.set_last(side_values.nth_distinct(i).unwrap_or_default() as f64)
+                        }
+                        Some(cli::Mode::Max) => {
+                            let n = side_values.distinct() as usize - 1 - i;
+                            result.set_last(side_values.nth_distinct(n).unwrap_or_default() as f64)
+                        }
+                        None | Some(cli::Mode::Rng) => {
+                            let side = result.last() as i64;
 
-                if value < 0 {
-                    return Err("Cannot drop a negative number of dice".into());
-                }
+                            if !seen.insert(side) {
+                                // rerolling until the die is unique is the same as rolling one
+                                // of the sides which weren't rolled yet, so only one reroll is shown
+                                let mut new_roll = sampler.sample(&mut rng);
 
-                if value > rolls {
-                    return Err("Cannot drop more dice than rolled".into());
-                }
+                                while seen.contains(&new_roll) {
+                                    env.check_time()?;
+                                    new_roll = sampler.sample(&mut rng);
+                                }
 
+                                env.count_dice(1)?;
+                                seen.insert(new_roll);
+                                result.reroll(new_roll as f64);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Modifier::SortAscending | Modifier::SortDescending => {
                 results.sort_by(|a, b| {
-                    a.sum(cli.mode.as_ref())
-                        .partial_cmp(&b.sum(cli.mode.as_ref()))
+                    a.rolled(env.cli.mode.as_ref())
+                        .partial_cmp(&b.rolled(env.cli.mode.as_ref()))
                         .expect("Cannot compare")
                 });
 
-                (0..value as usize).for_each(|i| results[i].drop());
-
-                results.reverse();
+                if matches!(modifier, Modifier::SortDescending) {
+                    results.reverse();
+                }
             }
             Modifier::Reroll { amount, condition } => {
-                let EvalResult { result, .. } = eval(amount, cli)?;
+                let EvalResult { result, .. } = eval(amount, env)?;
 
-                let value = result.round() as i64;
+                let value = result.as_int()?;
 
                 if value < 0 {
                     return Err("Cannot reroll a negative number of times".into());
                 }
 
+                check_depth(value, env)?;
+
                 let condition = if let Some(c) = condition {
                     let Condition { operator, value } = c;
 
-                    Some((operator, eval(value, cli)?))
+                    Some((operator, eval(value, env)?.result.as_number()?.to_f64()))
                 } else {
                     None
                 };
 
                 for result in results.iter_mut() {
+                    env.check_time()?;
+
                     match mode {
                         Some(cli::Mode::Avg) => {
-                            let len = side_values.len();
-
-                            if len == 0 {
+                            if side_values.is_empty() {
                                 continue;
                             }
 
-                            let avg =
-                                avg(&side_values.iter().map(|v| *v as f64).collect::<Vec<_>>());
+                            let avg = side_values.avg();
 
                             for i in 1..=value {
-                                let prob =
-                                    reroll_probability(side_values, i as u64, condition.clone())?;
+                                let prob = reroll_probability(side_values, i as u64, condition)?;
 
                                 let new_roll = prob * avg;
                                 result.reroll(new_roll);
@@ -375,7 +431,7 @@ fn apply_modifiers(
                         }
                         _ => {
                             for _ in 0..value {
-                                if let Some((operator, ref condition_value)) = condition {
+                                if let Some((operator, condition_value)) = condition {
                                     if !rel_op_eval(operator, result, condition_value)? {
                                         continue;
                                     }
@@ -383,81 +439,229 @@ fn apply_modifiers(
                                     continue;
                                 }
 
-                                let len = side_values.len();
-
-                                if len == 0 {
+                                if side_values.is_empty() {
                                     continue;
                                 }
 
-                                let new_roll = roller(1, side_values, &[], mode, cli)?;
+                                env.count_dice(1)?;
+                                let new_roll = roller(1, side_values, &[], mode, env)?;
                                 result.reroll(
-                                    new_roll.iter().map(|r| r.sum(cli.mode.as_ref())).sum(),
+                                    new_roll.iter().map(|r| r.sum(env.cli.mode.as_ref())).sum(),
                                 );
                             }
                         }
                     }
                 }
             }
-            Modifier::Explode { amount, condition } => {
-                let EvalResult { result, .. } = eval(amount, cli)?;
-
-                let value = result.round() as i64;
+            Modifier::Explode {
+                amount,
+                condition,
+                compound,
+            } => {
+                let value = match amount {
+                    Some(amount) => {
+                        let value = eval(amount, env)?.result.as_int()?;
+
+                        if value < 0 {
+                            return Err("Cannot explode a negative number of times".into());
+                        }
 
-                if value < 0 {
-                    return Err("Cannot reroll a negative number of times".into());
-                }
+                        check_depth(value, env)?;
+                        Some(value)
+                    }
+                    None => None,
+                };
 
                 let condition = if let Some(c) = condition {
                     let Condition { operator, value } = c;
 
-                    Some((operator, eval(value, cli)?))
+                    Some((operator, eval(value, env)?.result.as_number()?.to_f64()))
                 } else {
                     None
                 };
 
+                // sides which aren't known until now can still make the chain endless
+                if value.is_none() && explode_probability(side_values, 1, condition)? >= 1.0 {
+                    return Err("Cannot explode on every side, it would never stop".into());
+                }
+
                 for result in results.iter_mut() {
+                    env.check_time()?;
+
+                    let exploding = result.values.len() - 1;
+
                     match mode {
                         Some(cli::Mode::Avg) => {
-                            let len = side_values.len();
-
-                            if len == 0 {
+                            if side_values.is_empty() {
                                 continue;
                             }
 
-                            let avg =
-                                avg(&side_values.iter().map(|v| *v as f64).collect::<Vec<_>>());
+                            let avg = side_values.avg();
 
-                            for i in 1..=value {
-                                let prob =
-                                    explode_probability(side_values, i as u64, condition.clone())?;
+                            match value {
+                                Some(value) => {
+                                    for i in 1..=value {
+                                        let prob =
+                                            explode_probability(side_values, i as u64, condition)?;
 
-                                let new_roll = prob * avg;
-                                result.explode(new_roll);
+                                        let new_roll = prob * avg;
+                                        result.explode(new_roll);
+                                    }
+                                }
+                                None => {
+                                    // the expected value of the whole chain: avg * (p + p^2 + ...)
+                                    let prob = explode_probability(side_values, 1, condition)?;
+                                    result.explode(avg * prob / (1.0 - prob));
+                                }
                             }
                         }
                         _ => {
-                            for _ in 0..value {
-                                if let Some((operator, ref condition_value)) = condition {
+                            let mut explosions = 0;
+
+                            while value.is_none_or(|value| explosions < value) {
+                                if let Some((operator, condition_value)) = condition {
                                     if !rel_op_eval(operator, result, condition_value)? {
-                                        continue;
+                                        break;
                                     }
                                 } else if result.last() < result.max_side() as f64 {
-                                    continue;
+                                    break;
                                 }
 
-                                let len = side_values.len();
+                                if side_values.is_empty() {
+                                    break;
+                                }
 
-                                if len == 0 {
-                                    continue;
+                                if explosions as u64 >= env.limits.depth {
+                                    // at its highest a die would explode forever, so the
+                                    // chain is cut off at the limit and marked as such
+                                    if matches!(mode, Some(cli::Mode::Max)) {
+                                        result.cap();
+                                        break;
+                                    }
+
+                                    return Err(format!(
+                                        "A die exploded more than the limit of {} times",
+                                        env.limits.depth
+                                    )
+                                    .into());
                                 }
 
-                                let new_roll = roller(1, side_values, &[], mode, cli)?;
+                                env.count_dice(1)?;
+                                let new_roll = roller(1, side_values, &[], mode, env)?;
                                 result.explode(
-                                    new_roll.iter().map(|r| r.sum(cli.mode.as_ref())).sum(),
+                                    new_roll.iter().map(|r| r.sum(env.cli.mode.as_ref())).sum(),
                                 );
+
+                                explosions += 1;
                             }
                         }
                     }
+
+                    if *compound {
+                        result.compound(exploding);
+                    }
+                }
+            }
+            Modifier::Min(amount) | Modifier::Max(amount) => {
+                let value = eval(amount, env)?.result.as_number()?.to_f64();
+                let raise = matches!(modifier, Modifier::Min(_));
+
+                clamp = match raise {
+                    true => (clamp.0.max(value), clamp.1.max(value)),
+                    false => (clamp.0.min(value), clamp.1.min(value)),
+                };
+
+                for result in results.iter_mut() {
+                    let plain = result.values.len() == 1;
+
+                    let clamped = match mode {
+                        Some(cli::Mode::Avg) if plain => side_values.clamped_avg(clamp.0, clamp.1),
+                        Some(cli::Mode::Med) if plain => side_values.clamped_med(clamp.0, clamp.1),
+                        _ if raise => result.last().max(value),
+                        _ => result.last().min(value),
+                    };
+
+                    result.clamp_last(clamped);
+                }
+            }
+            Modifier::Bonus(amount) | Modifier::Penalty(amount) => {
+                let bonus = matches!(modifier, Modifier::Bonus(_));
+                let extra = eval(amount, env)?.result.as_int()?;
+
+                if extra < 0 {
+                    return Err("Cannot roll a negative number of bonus or penalty dice".into());
+                }
+
+                check_depth(extra, env)?;
+
+                if !side_values.is_percentile() {
+                    return Err(
+                        "Bonus and penalty dice only work on percentile dice like `d%`".into(),
+                    );
+                }
+
+                let mut rng = rand::thread_rng();
+
+                for result in results.iter_mut() {
+                    env.check_time()?;
+
+                    match mode {
+                        Some(cli::Mode::Avg) => {
+                            result.set_last(percentile::avg(extra as u64, bonus))
+                        }
+                        Some(cli::Mode::Med) => {
+                            result.set_last(percentile::med(extra as u64, bonus))
+                        }
+                        // the lowest and highest results can still be rolled
+                        Some(cli::Mode::Min | cli::Mode::Max) => {}
+                        _ => {
+                            let (mut tens, units) = percentile::split(result.last() as i64);
+
+                            for _ in 0..extra {
+                                env.count_dice(1)?;
+                                let other = rng.gen_range(0..10) * 10;
+
+                                // the lower result is better, and `00` with `0` is 100
+                                let lower =
+                                    percentile::join(other, units) < percentile::join(tens, units);
+
+                                if lower == bonus {
+                                    result.add_unused_tens(tens);
+                                    tens = other;
+                                } else {
+                                    result.add_unused_tens(other);
+                                }
+                            }
+
+                            result.set_last(percentile::join(tens, units) as f64);
+                        }
+                    }
+                }
+            }
+            Modifier::CriticalSuccess(condition) | Modifier::CriticalFailure(condition) => {
+                let critical = match modifier {
+                    Modifier::CriticalSuccess(_) => Critical::Success,
+                    _ => Critical::Failure,
+                };
+
+                let condition = if let Some(c) = condition {
+                    let Condition { operator, value } = c;
+
+                    Some((operator, eval(value, env)?.result.as_number()?.to_f64()))
+                } else {
+                    None
+                };
+
+                for result in results.iter_mut() {
+                    // without a condition, a critical is the highest or lowest side
+                    let (operator, value) = condition.unwrap_or(match critical {
+                        Critical::Success => (&RelOp::Equals, result.max_side() as f64),
+                        Critical::Failure => (&RelOp::Equals, result.min_side() as f64),
+                    });
+
+                    if rel_op_eval(operator, result, value)? {
+                        result.mark_critical(critical.clone());
+                    }
                 }
             }
         }
@@ -466,34 +670,32 @@ fn apply_modifiers(
     Ok(())
 }
 
+fn check_depth(depth: i64, env: &Env) -> Result<(), DynError> {
+    if depth as u64 > env.limits.depth {
+        return Err(format!(
+            "Cannot explode or reroll a die more than the limit of {} times",
+            env.limits.depth
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
 fn avg(values: &[f64]) -> f64 {
     let sum: f64 = values.iter().sum();
     sum / values.len() as f64
 }
 
-fn med(values: &[i64]) -> f64 {
-    let mut sorted = values.to_vec();
-    sorted.sort();
-
-    let len = sorted.len();
-
-    let mid = len / 2;
-
-    if len % 2 == 0 {
-        (sorted[mid - 1] + sorted[mid]) as f64 / 2.0
-    } else {
-        sorted[len / 2] as f64
-    }
-}
-
-fn rel_op_eval(operator: &RelOp, left: &DiceRolls, right: &EvalResult) -> Result<bool, DynError> {
-    let left = left.last();
-    let right = right.result;
-
-    rel_op_eval_value(operator, left, right)
+fn rel_op_eval(operator: &RelOp, left: &DiceRolls, right: f64) -> Result<bool, DynError> {
+    rel_op_eval_value(operator, left.last(), right)
 }
 
-fn rel_op_eval_value(operator: &RelOp, left: f64, right: f64) -> Result<bool, DynError> {
+pub fn rel_op_eval_value<T: PartialOrd>(
+    operator: &RelOp,
+    left: T,
+    right: T,
+) -> Result<bool, DynError> {
     Ok(match operator {
         RelOp::Equals => left == right,
         RelOp::NotEquals => left != right,
@@ -505,59 +707,31 @@ fn rel_op_eval_value(operator: &RelOp, left: f64, right: f64) -> Result<bool, Dy
 }
 
 fn explode_probability(
-    side_values: &[i64],
+    side_values: &SideValues,
     depth: u64,
-    condition: Option<(&RelOp, EvalResult)>,
+    condition: Option<(&RelOp, f64)>,
 ) -> Result<f64, DynError> {
-    let len = side_values.len();
-    let mut will_explode_count = 0;
-
-    let (op, rhs) = condition.map_or_else(
-        || {
-            (
-                &RelOp::Equals,
-                *side_values.iter().max().unwrap_or(&0) as f64,
-            )
-        },
-        |(op, ref result)| (op, result.result),
-    );
-
-    for v in side_values {
-        if rel_op_eval_value(op, *v as f64, rhs)? {
-            will_explode_count += 1;
-        }
-    }
+    let (op, rhs) = condition.unwrap_or((
+        &RelOp::Equals,
+        side_values.bounds().map_or(0, |b| b.1) as f64,
+    ));
 
-    let prob = will_explode_count as f64 / len as f64;
+    let prob = side_values.count_matching(op, rhs)? as f64 / side_values.len() as f64;
 
     Ok(prob.powf(depth as f64))
 }
 
 fn reroll_probability(
-    side_values: &[i64],
+    side_values: &SideValues,
     depth: u64,
-    condition: Option<(&RelOp, EvalResult)>,
+    condition: Option<(&RelOp, f64)>,
 ) -> Result<f64, DynError> {
-    let len = side_values.len();
-    let mut will_reroll_count = 0;
-
-    let (op, rhs) = condition.map_or_else(
-        || {
-            (
-                &RelOp::Equals,
-                *side_values.iter().min().unwrap_or(&0) as f64,
-            )
-        },
-        |(op, ref result)| (op, result.result),
-    );
-
-    for v in side_values {
-        if rel_op_eval_value(op, *v as f64, rhs)? {
-            will_reroll_count += 1;
-        }
-    }
+    let (op, rhs) = condition.unwrap_or((
+        &RelOp::Equals,
+        side_values.bounds().map_or(0, |b| b.0) as f64,
+    ));
 
-    let prob = will_reroll_count as f64 / len as f64;
+    let prob = side_values.count_matching(op, rhs)? as f64 / side_values.len() as f64;
 
     Ok(prob.powf(depth as f64))
 }
@@ -0,0 +1,229 @@
+use std::{cmp::Ordering, fmt::Display};
+
+use crate::program::DynError;
+
+/// A number which stays an exact integer until a float literal or a division
+/// turns it into a float.
+#[derive(Debug, Clone, Copy)]
+pub enum Number {
+    Int(i64),
+    Float(f64),
+}
+
+impl Number {
+    /// Converts a die value, which is a float so that modes like `avg` can
+    /// produce fractions, back into an integer when it is a whole number.
+    pub fn from_die(value: f64) -> Self {
+        // 2^53, the largest range in which every integer is an exact f64
+        const EXACT: f64 = 9_007_199_254_740_992.0;
+
+        if value.fract() == 0.0 && value.abs() <= EXACT {
+            Number::Int(value as i64)
+        } else {
+            Number::Float(value)
+        }
+    }
+
+    pub fn to_f64(self) -> f64 {
+        match self {
+            Number::Int(i) => i as f64,
+            Number::Float(f) => f,
+        }
+    }
+
+    /// The number rounded to the nearest integer.
+    pub fn to_i64(self) -> Result<i64, DynError> {
+        // 2^63, the first float which doesn't fit in an i64
+        const LIMIT: f64 = 9_223_372_036_854_775_808.0;
+
+        match self {
+            Number::Int(i) => Ok(i),
+            Number::Float(f) if !f.is_finite() => {
+                Err(format!("{} is not a finite number", f).into())
+            }
+            Number::Float(f) if f.round() >= LIMIT || f.round() < -LIMIT => {
+                Err(format!("{} is too large to be used as an integer", f).into())
+            }
+            Number::Float(f) => Ok(f.round() as i64),
+        }
+    }
+
+    pub fn is_zero(self) -> bool {
+        match self {
+            Number::Int(i) => i == 0,
+            Number::Float(f) => f == 0.0,
+        }
+    }
+
+    pub fn add(self, rhs: Number) -> Result<Number, DynError> {
+        self.arithmetic(rhs, "+", i64::checked_add, |a, b| a + b)
+    }
+
+    pub fn sub(self, rhs: Number) -> Result<Number, DynError> {
+        self.arithmetic(rhs, "-", i64::checked_sub, |a, b| a - b)
+    }
+
+    pub fn mul(self, rhs: Number) -> Result<Number, DynError> {
+        self.arithmetic(rhs, "*", i64::checked_mul, |a, b| a * b)
+    }
+
+    /// Division always produces a float, use [`Number::int_div`] to stay an integer.
+    pub fn div(self, rhs: Number) -> Result<Number, DynError> {
+        if rhs.is_zero() {
+            return Err("Division by zero".into());
+        }
+
+        finite(self.to_f64() / rhs.to_f64())
+    }
+
+    /// Division rounded down towards negative infinity.
+    pub fn int_div(self, rhs: Number) -> Result<Number, DynError> {
+        if rhs.is_zero() {
+            return Err("Division by zero".into());
+        }
+
+        self.arithmetic(rhs, "//", floor_div, |a, b| (a / b).floor())
+    }
+
+    pub fn rem(self, rhs: Number) -> Result<Number, DynError> {
+        if rhs.is_zero() {
+            return Err("Modulo by zero".into());
+        }
+
+        self.arithmetic(rhs, "%", i64::checked_rem, |a, b| a % b)
+    }
+
+    pub fn pow(self, rhs: Number) -> Result<Number, DynError> {
+        match (self, rhs) {
+            (Number::Int(base), Number::Int(exp)) if exp >= 0 => u32::try_from(exp)
+                .ok()
+                .and_then(|exp| base.checked_pow(exp))
+                .map(Number::Int)
+                .ok_or_else(|| overflow(base, "^", exp)),
+            (base, exp) => finite(base.to_f64().powf(exp.to_f64())),
+        }
+    }
+
+    pub fn neg(self) -> Result<Number, DynError> {
+        match self {
+            Number::Int(i) => i
+                .checked_neg()
+                .map(Number::Int)
+                .ok_or_else(|| format!("Integer overflow evaluating -{}", i).into()),
+            Number::Float(f) => Ok(Number::Float(-f)),
+        }
+    }
+
+    /// Orders integers exactly and everything else as floats.
+    pub fn total_cmp(&self, other: &Number) -> Ordering {
+        match (self, other) {
+            (Number::Int(a), Number::Int(b)) => a.cmp(b),
+            (a, b) => a.to_f64().total_cmp(&b.to_f64()),
+        }
+    }
+
+    fn arithmetic(
+        self,
+        rhs: Number,
+        symbol: &str,
+        int_op: fn(i64, i64) -> Option<i64>,
+        float_op: fn(f64, f64) -> f64,
+    ) -> Result<Number, DynError> {
+        match (self, rhs) {
+            (Number::Int(a), Number::Int(b)) => int_op(a, b)
+                .map(Number::Int)
+                .ok_or_else(|| overflow(a, symbol, b)),
+            (a, b) => finite(float_op(a.to_f64(), b.to_f64())),
+        }
+    }
+}
+
+/// Rejects NaN and infinity, so they can't silently turn into 0 dice later on.
+fn finite(value: f64) -> Result<Number, DynError> {
+    if value.is_finite() {
+        Ok(Number::Float(value))
+    } else {
+        Err(format!("Result is {}, which is not a finite number", value).into())
+    }
+}
+
+fn floor_div(a: i64, b: i64) -> Option<i64> {
+    let quotient = a.checked_div(b)?;
+
+    if a % b != 0 && (a < 0) != (b < 0) {
+        Some(quotient - 1)
+    } else {
+        Some(quotient)
+    }
+}
+
+fn overflow(a: i64, symbol: &str, b: i64) -> DynError {
+    format!("Integer overflow evaluating {} {} {}", a, symbol, b).into()
+}
+
+impl PartialEq for Number {
+    fn eq(&self, other: &Self) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Number::Int(a), Number::Int(b)) => a.partial_cmp(b),
+            (a, b) => a.to_f64().partial_cmp(&b.to_f64()),
+        }
+    }
+}
+
+impl Display for Number {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Number::Int(i) => write!(f, "{i}"),
+            Number::Float(v) => write!(f, "{v}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_large_integers_stay_exact() {
+        let result = Number::Int(17)
+            .mul(Number::Int(1_000_000_000_000_001))
+            .unwrap();
+        assert!(matches!(result, Number::Int(17_000_000_000_000_017)));
+    }
+
+    #[test]
+    fn test_overflow_is_an_error() {
+        assert!(Number::Int(i64::MAX).add(Number::Int(1)).is_err());
+        assert!(Number::Int(10).pow(Number::Int(19)).is_err());
+    }
+
+    #[test]
+    fn test_division() {
+        assert!(matches!(
+            Number::Int(7).div(Number::Int(2)).unwrap(),
+            Number::Float(3.5)
+        ));
+        assert!(matches!(
+            Number::Int(-7).int_div(Number::Int(2)).unwrap(),
+            Number::Int(-4)
+        ));
+        assert!(Number::Int(7).int_div(Number::Int(0)).is_err());
+        assert!(Number::Float(7.0).div(Number::Int(0)).is_err());
+        assert!(Number::Float(7.0).rem(Number::Float(0.0)).is_err());
+    }
+
+    #[test]
+    fn test_not_finite() {
+        assert!(Number::Float(-8.0).pow(Number::Float(0.5)).is_err());
+        assert!(Number::Float(10.0).pow(Number::Int(400)).is_err());
+        assert!(Number::Float(f64::NAN).to_i64().is_err());
+        assert!(Number::Float(1e19).to_i64().is_err());
+        assert_eq!(Number::Float(2.5).to_i64().unwrap(), 3);
+    }
+}
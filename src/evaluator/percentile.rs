@@ -0,0 +1,122 @@
+/// The sides of a percentile die, which is rolled as a tens die and a units die.
+pub const SIDES: (i64, i64) = (1, 100);
+
+/// Splits a percentile roll into its tens die, from 0 to 90, and its units die.
+/// 100 is rolled as `00` and `0`.
+pub fn split(value: i64) -> (i64, i64) {
+    (value % 100 / 10 * 10, value % 10)
+}
+
+/// The roll shown by a tens die and a units die, the inverse of `split`.
+pub fn join(tens: i64, units: i64) -> i64 {
+    match tens + units {
+        0 => 100,
+        value => value,
+    }
+}
+
+/// The chance of every result of a percentile die rolled with `extra` bonus
+/// dice, or penalty dice if `bonus` is false, lowest result first.
+///
+/// Every extra die is another tens die, and the tens die which gives the best
+/// (lowest) or worst (highest) result is used, so `00` only counts as the best
+/// tens die when the units die isn't `0`.
+pub fn distribution(extra: u64, bonus: bool) -> Vec<(i64, f64)> {
+    let dice = extra as i32 + 1;
+    let mut chances = vec![];
+
+    for units in 0..10 {
+        let mut results = (0..10)
+            .map(|tens| join(tens * 10, units))
+            .collect::<Vec<_>>();
+        results.sort();
+
+        for (i, result) in results.into_iter().enumerate() {
+            // the chance that the best or worst of all tens dice gives the `i`th lowest result
+            let chance = match bonus {
+                true => ((10 - i) as f64 / 10.0).powi(dice) - ((9 - i) as f64 / 10.0).powi(dice),
+                false => ((i + 1) as f64 / 10.0).powi(dice) - (i as f64 / 10.0).powi(dice),
+            };
+
+            chances.push((result, chance / 10.0));
+        }
+    }
+
+    chances.sort_by_key(|(result, _)| *result);
+    chances
+}
+
+pub fn avg(extra: u64, bonus: bool) -> f64 {
+    let avg = distribution(extra, bonus)
+        .iter()
+        .map(|(result, chance)| *result as f64 * chance)
+        .sum::<f64>();
+
+    // the chances add up to an exact average, apart from floating point noise
+    (avg * 1e9).round() / 1e9
+}
+
+/// The median result, halfway between two results if exactly half of the rolls
+/// are at most the first, like the median of a plain `d100`.
+pub fn med(extra: u64, bonus: bool) -> f64 {
+    let distribution = distribution(extra, bonus);
+    let mut cumulative = 0.0;
+
+    for (i, (result, chance)) in distribution.iter().enumerate() {
+        cumulative += chance;
+
+        if (cumulative - 0.5).abs() < 1e-9 {
+            return (*result as f64 + distribution[i + 1].0 as f64) / 2.0;
+        }
+
+        if cumulative > 0.5 {
+            return *result as f64;
+        }
+    }
+
+    SIDES.1 as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_and_join() {
+        assert_eq!(split(73), (70, 3));
+        assert_eq!(split(5), (0, 5));
+        assert_eq!(split(100), (0, 0));
+
+        for value in 1..=100 {
+            let (tens, units) = split(value);
+            assert_eq!(join(tens, units), value);
+        }
+    }
+
+    #[test]
+    fn test_plain_distribution() {
+        assert!((avg(0, true) - 50.5).abs() < 1e-9);
+        assert!((med(0, false) - 50.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_bonus_and_penalty() {
+        for extra in 1..=2 {
+            let total = distribution(extra, true)
+                .iter()
+                .map(|(_, c)| c)
+                .sum::<f64>();
+            assert!((total - 1.0).abs() < 1e-9);
+
+            assert!(avg(extra, true) < avg(extra - 1, true));
+            assert!(avg(extra, false) > avg(extra - 1, false));
+        }
+
+        // with one bonus die, 100 needs `00` on both tens dice and `0` on the units die
+        let hundred = distribution(1, true)
+            .into_iter()
+            .find(|(result, _)| *result == 100)
+            .unwrap();
+        assert!((hundred.1 - 0.001).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,368 @@
+use rand::Rng;
+
+use crate::{parser::RelOp, program::DynError};
+
+use super::mode::rel_op_eval_value;
+
+/// The sides of a die. Ranges are kept as their bounds, so a die like
+/// `d1000000000` can be rolled without listing every side.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SideValues {
+    /// Every side from `min` to `max`, inclusive.
+    Range { min: i64, max: i64 },
+    /// Every listed side, where a side listed twice is twice as likely.
+    Values(Vec<i64>),
+    /// Sides with how many times each one is on the die, like `d{1: 1, 6: 4}`.
+    Weighted(Vec<(i64, u64)>),
+}
+
+impl SideValues {
+    /// Every side with how many times it is on the die, lowest side first.
+    /// Panics for a range, which can be too big to list.
+    pub fn weights(&self) -> Vec<(i64, u64)> {
+        let mut weights = match self {
+            SideValues::Range { .. } => unreachable!("the sides of a range aren't listed"),
+            SideValues::Values(values) => values.iter().map(|v| (*v, 1)).collect::<Vec<_>>(),
+            SideValues::Weighted(weights) => {
+                weights.iter().filter(|(_, w)| *w > 0).copied().collect()
+            }
+        };
+
+        weights.sort();
+        weights
+    }
+
+    /// The `n`th lowest listed side, counting from 0 and counting every side as
+    /// many times as it is on the die.
+    fn nth_listed(&self, n: u128) -> Option<i64> {
+        let mut n = n;
+
+        for (side, weight) in self.weights() {
+            if n < weight as u128 {
+                return Some(side);
+            }
+
+            n -= weight as u128;
+        }
+
+        None
+    }
+
+    /// Whether every side from 1 to 100 is equally likely, like on a percentile die,
+    /// which is rolled as a tens die and a units die.
+    pub fn is_percentile(&self) -> bool {
+        self.bounds() == Some((1, 100)) && self.len() == 100 && self.distinct() == 100
+    }
+
+    /// The number of sides. This is an `i128` since `d[-9223372036854775808..9223372036854775807]`
+    /// has one more side than fits in a `u64`.
+    pub fn len(&self) -> i128 {
+        match self {
+            SideValues::Range { min, max } => (*max as i128 - *min as i128 + 1).max(0),
+            SideValues::Values(values) => values.len() as i128,
+            SideValues::Weighted(_) => self.weights().iter().map(|(_, w)| *w as i128).sum(),
+        }
+    }
+
+    /// The number of different sides, since a die like `d[1, 2, 2]` has a side twice.
+    pub fn distinct(&self) -> i128 {
+        match self {
+            SideValues::Range { .. } => self.len(),
+            _ => {
+                let mut sides = self.weights();
+                sides.dedup_by_key(|(side, _)| *side);
+                sides.len() as i128
+            }
+        }
+    }
+
+    /// The `n`th lowest different side, counting from 0.
+    pub fn nth_distinct(&self, n: usize) -> Option<i64> {
+        match self {
+            SideValues::Range { min, .. } => {
+                let side = *min as i128 + n as i128;
+                (side < *min as i128 + self.len()).then_some(side as i64)
+            }
+            _ => {
+                let mut sides = self.weights();
+                sides.dedup_by_key(|(side, _)| *side);
+                sides.get(n).map(|(side, _)| *side)
+            }
+        }
+    }
+
+    /// The `n`th lowest side, counting from 0 and counting repeated sides every time.
+    pub fn nth_lowest(&self, n: usize) -> Option<i64> {
+        match self {
+            SideValues::Range { .. } => self.nth_distinct(n),
+            _ => self.nth_listed(n as u128),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The lowest and highest side, or `None` for a die without sides.
+    pub fn bounds(&self) -> Option<(i64, i64)> {
+        match self {
+            SideValues::Range { min, max } if min <= max => Some((*min, *max)),
+            SideValues::Range { .. } => None,
+            _ => {
+                let sides = self.weights();
+                Some((sides.first()?.0, sides.last()?.0))
+            }
+        }
+    }
+
+    /// Prepares the die for picking many random sides, like for every die of a roll.
+    pub fn sampler(&self) -> Sampler<'_> {
+        match self {
+            SideValues::Weighted(_) => {
+                let mut total = 0;
+
+                let ends = self
+                    .weights()
+                    .into_iter()
+                    .map(|(side, weight)| {
+                        total += weight as u128;
+                        (total, side)
+                    })
+                    .collect();
+
+                Sampler::Weighted { ends, total }
+            }
+            sides => Sampler::Sides(sides),
+        }
+    }
+
+    pub fn avg(&self) -> f64 {
+        match self {
+            SideValues::Range { min, max } => (*min as f64 + *max as f64) / 2.0,
+            _ => {
+                self.weights()
+                    .iter()
+                    .map(|(side, weight)| *side as f64 * *weight as f64)
+                    .sum::<f64>()
+                    / self.len() as f64
+            }
+        }
+    }
+
+    pub fn med(&self) -> f64 {
+        let (low, high) = self.middle();
+        (low as f64 + high as f64) / 2.0
+    }
+
+    /// The middle side, twice if there is an odd number of sides, or the two
+    /// sides around the middle otherwise. Panics if there are no sides.
+    fn middle(&self) -> (i64, i64) {
+        let len = self.len();
+
+        match self {
+            SideValues::Range { min, .. } => {
+                let low = *min as i128 + (len - 1) / 2;
+                let high = *min as i128 + len / 2;
+                (low as i64, high as i64)
+            }
+            _ => {
+                let len = len as u128;
+                let low = self.nth_listed((len - 1) / 2).expect("No sides");
+                let high = self.nth_listed(len / 2).expect("No sides");
+                (low, high)
+            }
+        }
+    }
+
+    /// The average of the die when every side is raised to at least `low`
+    /// and lowered to at most `high`, where `low <= high`.
+    pub fn clamped_avg(&self, low: f64, high: f64) -> f64 {
+        let (min, max) = match self {
+            SideValues::Range { min, max } => (*min as i128, *max as i128),
+            _ => {
+                return self
+                    .weights()
+                    .iter()
+                    .map(|(side, weight)| (*side as f64).clamp(low, high) * *weight as f64)
+                    .sum::<f64>()
+                    / self.len() as f64;
+            }
+        };
+
+        let len = self.len();
+
+        // float to int casts saturate, so infinite bounds count no sides
+        let below = (low.ceil() as i128).saturating_sub(min).clamp(0, len);
+        let above = max.saturating_sub(high.floor() as i128).clamp(0, len);
+        let between = (len - below - above).max(0);
+
+        // the sides in between keep their value, and add up like any range
+        let first = (min + below) as f64;
+        let last = (max - above) as f64;
+        let mut sum = (first + last) / 2.0 * between as f64;
+
+        if below > 0 {
+            sum += below as f64 * low;
+        }
+
+        if above > 0 {
+            sum += above as f64 * high;
+        }
+
+        sum / len as f64
+    }
+
+    /// The median of the die when every side is clamped like in `clamped_avg`.
+    /// Clamping keeps the order of the sides, so it clamps the middle sides.
+    pub fn clamped_med(&self, low: f64, high: f64) -> f64 {
+        let (first, second) = self.middle();
+        ((first as f64).clamp(low, high) + (second as f64).clamp(low, high)) / 2.0
+    }
+
+    /// The number of sides `v` for which `v {operator} rhs` holds.
+    pub fn count_matching(&self, operator: &RelOp, rhs: f64) -> Result<i128, DynError> {
+        let (min, max) = match self {
+            SideValues::Range { min, max } => (*min as i128, *max as i128),
+            _ => {
+                let mut count = 0;
+
+                for (side, weight) in self.weights() {
+                    if rel_op_eval_value(operator, side as f64, rhs)? {
+                        count += weight as i128;
+                    }
+                }
+
+                return Ok(count);
+            }
+        };
+
+        // float to int casts saturate, so thresholds far outside the range are fine
+        let between = |low: i128, high: i128| (high.min(max) - low.max(min) + 1).max(0);
+        let equal = if rhs.fract() == 0.0 {
+            between(rhs as i128, rhs as i128)
+        } else {
+            0
+        };
+
+        Ok(match operator {
+            RelOp::Equals => equal,
+            RelOp::NotEquals => self.len() - equal,
+            RelOp::Greater => between(rhs.floor() as i128 + 1, max),
+            RelOp::GreaterEqual => between(rhs.ceil() as i128, max),
+            RelOp::Less => between(min, rhs.ceil() as i128 - 1),
+            RelOp::LessEqual => between(min, rhs.floor() as i128),
+        })
+    }
+}
+
+/// Picks random sides of a die. The weights of a weighted die are added up once,
+/// instead of for every die which is rolled.
+pub enum Sampler<'a> {
+    Sides(&'a SideValues),
+    /// The running total of the weights up to and including every side.
+    Weighted {
+        ends: Vec<(u128, i64)>,
+        total: u128,
+    },
+}
+
+impl Sampler<'_> {
+    /// Picks a random side. Panics if there are no sides.
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> i64 {
+        match self {
+            Sampler::Sides(SideValues::Range { min, max }) => rng.gen_range(*min..=*max),
+            Sampler::Sides(SideValues::Values(values)) => values[rng.gen_range(0..values.len())],
+            Sampler::Sides(SideValues::Weighted(_)) => {
+                unreachable!("weighted sides are sampled from their running total")
+            }
+            Sampler::Weighted { ends, total } => {
+                let n = rng.gen_range(0..*total);
+                ends[ends.partition_point(|(end, _)| *end <= n)].1
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_huge_range() {
+        let sides = SideValues::Range {
+            min: 1,
+            max: 1_000_000_000_000,
+        };
+
+        assert_eq!(sides.len(), 1_000_000_000_000);
+        assert_eq!(sides.avg(), 500_000_000_000.5);
+
+        let side = sides.sampler().sample(&mut rand::thread_rng());
+        assert!((1..=1_000_000_000_000).contains(&side));
+    }
+
+    #[test]
+    fn test_weighted() {
+        let weighted = SideValues::Weighted(vec![(1, 1), (2, 1), (3, 2), (6, 4)]);
+        let repeated = SideValues::Values(vec![6, 1, 3, 6, 2, 6, 3, 6]);
+
+        assert_eq!(weighted.len(), 8);
+        assert_eq!(weighted.distinct(), 4);
+        assert_eq!(weighted.bounds(), Some((1, 6)));
+        assert_eq!(weighted.avg(), repeated.avg());
+        assert_eq!(weighted.med(), 4.5);
+        assert_eq!(weighted.med(), repeated.med());
+        assert_eq!(
+            weighted.count_matching(&RelOp::Equals, 6.0).unwrap(),
+            repeated.count_matching(&RelOp::Equals, 6.0).unwrap()
+        );
+
+        let side = weighted.sampler().sample(&mut rand::thread_rng());
+        assert!([1, 2, 3, 6].contains(&side));
+    }
+
+    #[test]
+    fn test_clamped() {
+        let range = SideValues::Range { min: 1, max: 6 };
+        let values = SideValues::Values(vec![1, 2, 3, 4, 5, 6]);
+
+        for (low, high) in [(3.0, 5.0), (f64::NEG_INFINITY, 2.0), (4.5, 4.5), (0.0, 9.0)] {
+            assert_eq!(
+                range.clamped_avg(low, high),
+                values.clamped_avg(low, high),
+                "{} {}",
+                low,
+                high
+            );
+            assert_eq!(range.clamped_med(low, high), values.clamped_med(low, high));
+        }
+
+        assert_eq!(range.clamped_avg(3.0, f64::INFINITY), 4.0);
+        assert_eq!(range.clamped_med(f64::NEG_INFINITY, f64::INFINITY), 3.5);
+    }
+
+    #[test]
+    fn test_count_matching() {
+        let range = SideValues::Range { min: 1, max: 6 };
+        let values = SideValues::Values(vec![1, 2, 3, 4, 5, 6]);
+
+        for operator in [
+            RelOp::Equals,
+            RelOp::NotEquals,
+            RelOp::Greater,
+            RelOp::GreaterEqual,
+            RelOp::Less,
+            RelOp::LessEqual,
+        ] {
+            for rhs in [-3.0, 1.0, 3.5, 4.0, 6.0, 10.0] {
+                assert_eq!(
+                    range.count_matching(&operator, rhs).unwrap(),
+                    values.count_matching(&operator, rhs).unwrap(),
+                    "{} {}",
+                    operator,
+                    rhs
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use std::fs;
+
+use crate::{
+    analyzer::analyze,
+    lexer::tokenize,
+    parser::{parse, Cursor, Expr},
+    path_utils::get_path,
+    program::DynError,
+};
+
+/// A random table, which maps ranges of a roll to entries, one row per line:
+///
+/// ```text
+/// # encounters
+/// 1-3: 2d4 goblins
+/// 4-5: 1d2 orcs
+/// 6: a troll
+/// ```
+#[derive(Debug, Clone)]
+pub struct Table {
+    rows: Vec<Row>,
+}
+
+#[derive(Debug, Clone)]
+struct Row {
+    min: i64,
+    max: i64,
+    entry: Vec<Part>,
+}
+
+/// A word of an entry. Words like `2d4` are rolled every time the entry comes up.
+#[derive(Debug, Clone)]
+pub enum Part {
+    Text(String),
+    Roll(Expr),
+}
+
+impl Table {
+    /// Reads the table in the file at `path`.
+    pub fn load(path: &str) -> Result<Self, DynError> {
+        let full_path = get_path(path)?;
+        let contents = fs::read_to_string(&full_path)
+            .map_err(|e| format!("Failed to read table {:?}: {}", full_path, e))?;
+
+        Self::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, DynError> {
+        let mut rows: Vec<Row> = vec![];
+
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (range, entry) = line
+                .split_once(':')
+                .ok_or_else(|| format!("Expected a row like `1-3: goblin`, found '{}'", line))?;
+
+            let (min, max) = parse_range(range.trim())
+                .ok_or_else(|| format!("Invalid range '{}' in '{}'", range.trim(), line))?;
+
+            if let Some(row) = rows.iter().find(|row| min <= row.max && row.min <= max) {
+                return Err(format!(
+                    "The rows for {}-{} and {}-{} overlap",
+                    row.min, row.max, min, max
+                )
+                .into());
+            }
+
+            rows.push(Row {
+                min,
+                max,
+                entry: parse_entry(entry.trim()),
+            });
+        }
+
+        if rows.is_empty() {
+            return Err("Table has no rows".into());
+        }
+
+        Ok(Self { rows })
+    }
+
+    /// The lowest and highest roll with an entry.
+    pub fn bounds(&self) -> (i64, i64) {
+        let min = self.rows.iter().map(|row| row.min).min();
+        let max = self.rows.iter().map(|row| row.max).max();
+
+        (min.expect("Table has rows"), max.expect("Table has rows"))
+    }
+
+    /// The entry for `roll`, or `None` if no row covers it.
+    pub fn lookup(&self, roll: i64) -> Option<&[Part]> {
+        self.rows
+            .iter()
+            .find(|row| row.min <= roll && roll <= row.max)
+            .map(|row| row.entry.as_slice())
+    }
+}
+
+/// Parses `4` or `4-6`.
+fn parse_range(range: &str) -> Option<(i64, i64)> {
+    let (min, max) = match range.split_once('-') {
+        Some((min, max)) => (min.trim().parse().ok()?, max.trim().parse().ok()?),
+        None => {
+            let value = range.parse().ok()?;
+            (value, value)
+        }
+    };
+
+    (min <= max).then_some((min, max))
+}
+
+/// Splits an entry into words, keeping the spaces between them as text.
+fn parse_entry(entry: &str) -> Vec<Part> {
+    let mut parts = vec![];
+
+    for (i, word) in entry.split(' ').enumerate() {
+        if i > 0 {
+            parts.push(Part::Text(" ".to_string()));
+        }
+
+        // punctuation after a roll, like in `2d4, 1d6.`, is part of the text
+        let core = word.trim_end_matches([',', '.', ';']);
+
+        match parse_roll(core) {
+            Some(roll) => {
+                parts.push(Part::Roll(roll));
+                parts.push(Part::Text(word[core.len()..].to_string()));
+            }
+            None => parts.push(Part::Text(word.to_string())),
+        }
+    }
+
+    parts
+}
+
+/// A word which is an expression to evaluate, like `2d4` or `max(1d6,1d6)`.
+/// Words without dice, like `3`, `goblins` or `half-orc`, are kept as they are.
+fn parse_roll(word: &str) -> Option<Expr> {
+    let mut cursor = Cursor::new(tokenize(word).ok()?);
+    let expr = parse(&mut cursor).and_then(analyze).ok()?;
+
+    contains_roll(&expr).then_some(expr)
+}
+
+fn contains_roll(expr: &Expr) -> bool {
+    match expr {
+        Expr::Roll { .. } | Expr::Draw { .. } | Expr::Group { .. } | Expr::Table { .. } => true,
+        Expr::Int(_) | Expr::Float(_) | Expr::Ident(_) => false,
+        Expr::Call { args, .. } => args.iter().any(contains_roll),
+        Expr::Additive { left, right, .. }
+        | Expr::Multiplicative { left, right, .. }
+        | Expr::Exponential { left, right, .. }
+        | Expr::Relational { left, right, .. }
+        | Expr::Logical { left, right, .. } => contains_roll(left) || contains_roll(right),
+        Expr::Negate(expr) | Expr::Not(expr) => contains_roll(expr),
+        Expr::Repeat { count, expr } => contains_roll(count) || contains_roll(expr),
+        Expr::Where { expr, condition } => contains_roll(expr) || contains_roll(&condition.value),
+        Expr::If {
+            condition,
+            then,
+            otherwise,
+        } => contains_roll(condition) || contains_roll(then) || contains_roll(otherwise),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(parts: &[Part]) -> String {
+        parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.clone(),
+                Part::Roll(roll) => format!("<{}>", roll),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_lookup() {
+        let table = Table::parse(
+            "# encounters\n1-3: 2d4 goblins\n4: orc\n5: 1d2 half-orc\n\n6: 1 troll, d2.",
+        )
+        .unwrap();
+
+        assert_eq!(table.bounds(), (1, 6));
+        assert_eq!(text(table.lookup(2).unwrap()), "<2d4> goblins");
+        assert_eq!(text(table.lookup(4).unwrap()), "orc");
+        assert_eq!(text(table.lookup(5).unwrap()), "<1d2> half-orc");
+        assert_eq!(text(table.lookup(6).unwrap()), "1 troll, <1d2>.");
+        assert!(table.lookup(7).is_none());
+    }
+
+    #[test]
+    fn test_invalid_tables() {
+        assert!(Table::parse("").is_err());
+        assert!(Table::parse("goblin").is_err());
+        assert!(Table::parse("3-1: goblin").is_err());
+        assert!(Table::parse("1-3: goblin\n3-4: orc").is_err());
+    }
+}
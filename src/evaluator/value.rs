@@ -0,0 +1,286 @@
+use std::fmt::Display;
+
+use rand::seq::SliceRandom;
+
+use crate::{parser::RelOp, program::DynError};
+
+use super::{mode::rel_op_eval_value, Number};
+
+/// The result of evaluating an expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Number(Number),
+    Bool(bool),
+    List(Vec<Value>),
+    /// The dice kept by a roll, before they are summed. A pool only collapses
+    /// to its sum when it is used as a number or printed.
+    Pool(Vec<Number>),
+    /// A pool of a roll too big to keep every die, as how many dice show each
+    /// face, lowest face first. It works like a pool.
+    Tally(Vec<(Number, u64)>),
+    /// `width` dice which all show `height`, as used by the One-Roll Engine.
+    Set {
+        width: usize,
+        height: Number,
+    },
+    /// An entry of a random table, with its rolls filled in.
+    Text(String),
+}
+
+impl Value {
+    /// The value as a number. Booleans count as 1 for true and 0 for false,
+    /// so comparisons can be added up to count successes.
+    pub fn as_number(&self) -> Result<Number, DynError> {
+        match self {
+            Value::Number(n) => Ok(*n),
+            Value::Bool(b) => Ok(Number::Int(*b as i64)),
+            Value::List(_) => Err(format!("Expected a number, found the list {}", self).into()),
+            Value::Pool(dice) => sum(dice),
+            Value::Tally(faces) => tally_sum(faces),
+            Value::Set { .. } => Err(format!(
+                "Expected a number, found the set {}. Use width() or height()",
+                self
+            )
+            .into()),
+            Value::Text(_) => Err(format!("Expected a number, found the text '{}'", self).into()),
+        }
+    }
+
+    /// The value as an integer, for things like the number of dice to roll.
+    /// Floats are rounded to the nearest integer.
+    pub fn as_int(&self) -> Result<i64, DynError> {
+        self.as_number()?.to_i64()
+    }
+
+    /// The value as a boolean. Any number other than 0 is true, and a pool
+    /// is true if it has any dice left in it.
+    pub fn as_bool(&self) -> Result<bool, DynError> {
+        match self {
+            Value::Number(n) => Ok(!n.is_zero()),
+            Value::Bool(b) => Ok(*b),
+            Value::List(_) => Err(format!("Expected a boolean, found the list {}", self).into()),
+            Value::Pool(dice) => Ok(!dice.is_empty()),
+            Value::Tally(faces) => Ok(faces.iter().any(|(_, count)| *count > 0)),
+            Value::Set { .. } => Err(format!("Expected a boolean, found the set {}", self).into()),
+            Value::Text(_) => Err(format!("Expected a boolean, found the text '{}'", self).into()),
+        }
+    }
+
+    /// The numbers in the value, with nested lists flattened. A pool is split
+    /// into its dice, but a pool inside a list is one number, its sum, so
+    /// `max(6x(3d6))` is the highest of six totals.
+    pub fn numbers(&self) -> Result<Vec<Number>, DynError> {
+        match self {
+            Value::List(values) => Ok(values
+                .iter()
+                .map(|v| match v {
+                    Value::List(_) => v.numbers(),
+                    other => Ok(vec![other.as_number()?]),
+                })
+                .collect::<Result<Vec<_>, _>>()?
+                .concat()),
+            Value::Pool(dice) => Ok(dice.clone()),
+            Value::Tally(faces) => Ok(dice(faces)),
+            other => Ok(vec![other.as_number()?]),
+        }
+    }
+
+    /// Keeps the dice of a pool, or the elements of a list, which satisfy
+    /// `operator` against `value`.
+    pub fn filter(&self, operator: &RelOp, value: Number) -> Result<Value, DynError> {
+        match self {
+            Value::Pool(dice) => {
+                let mut kept = vec![];
+
+                for die in dice {
+                    if rel_op_eval_value(operator, *die, value)? {
+                        kept.push(*die);
+                    }
+                }
+
+                Ok(Value::Pool(kept))
+            }
+            Value::Tally(faces) => {
+                let mut kept = vec![];
+
+                for (face, count) in faces {
+                    if rel_op_eval_value(operator, *face, value)? {
+                        kept.push((*face, *count));
+                    }
+                }
+
+                Ok(Value::Tally(kept))
+            }
+            Value::List(values) => {
+                let mut kept = vec![];
+
+                for v in values {
+                    if rel_op_eval_value(operator, v.as_number()?, value)? {
+                        kept.push(v.clone());
+                    }
+                }
+
+                Ok(Value::List(kept))
+            }
+            other => Err(format!("Expected a pool or a list to filter, found {}", other).into()),
+        }
+    }
+
+    /// The number of dice in a pool or elements in a list.
+    pub fn count(&self) -> Result<usize, DynError> {
+        match self {
+            Value::Pool(dice) => Ok(dice.len()),
+            Value::Tally(faces) => Ok(faces.iter().map(|(_, count)| *count as usize).sum()),
+            Value::List(values) => Ok(values.len()),
+            other => Err(format!("Expected a pool or a list to count, found {}", other).into()),
+        }
+    }
+
+    /// The first `n` dice of a pool or elements of a list. A tally doesn't know
+    /// which die came first, so `n` of its dice are picked at random.
+    pub fn take(&self, n: usize) -> Result<Value, DynError> {
+        match self {
+            Value::Pool(dice) => Ok(Value::Pool(dice.iter().take(n).copied().collect())),
+            Value::Tally(faces) => {
+                let mut dice = dice(faces);
+                let (taken, _) = dice.partial_shuffle(&mut rand::thread_rng(), n);
+
+                Ok(Value::Pool(taken.to_vec()))
+            }
+            Value::List(values) => Ok(Value::List(values.iter().take(n).cloned().collect())),
+            other => Err(format!("Expected a pool or a list to take from, found {}", other).into()),
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Number(n) => write!(f, "{n}"),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::List(values) => write!(
+                f,
+                "[{}]",
+                values
+                    .iter()
+                    .map(|v| v.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            // a sum too large for an integer is still worth printing approximately
+            Value::Pool(dice) => match sum(dice) {
+                Ok(n) => write!(f, "{n}"),
+                Err(_) => write!(f, "{}", dice.iter().map(|d| d.to_f64()).sum::<f64>()),
+            },
+            Value::Tally(faces) => match tally_sum(faces) {
+                Ok(n) => write!(f, "{n}"),
+                Err(_) => write!(
+                    f,
+                    "{}",
+                    faces
+                        .iter()
+                        .map(|(face, count)| face.to_f64() * *count as f64)
+                        .sum::<f64>()
+                ),
+            },
+            Value::Set { width, height } => write!(f, "{width}x{height}"),
+            Value::Text(text) => write!(f, "{text}"),
+        }
+    }
+}
+
+/// Groups equal values into sets, widest first and then highest first.
+pub fn group_sets(values: &[Number]) -> Vec<(usize, Number)> {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| b.total_cmp(a));
+
+    let mut sets: Vec<(usize, Number)> = vec![];
+
+    for value in sorted {
+        match sets.last_mut() {
+            Some((width, height)) if *height == value => *width += 1,
+            _ => sets.push((1, value)),
+        }
+    }
+
+    sets.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.total_cmp(&a.1)));
+    sets
+}
+
+/// Adds up `values`, starting from an integer zero so an empty pool prints as `0`.
+pub fn sum(values: &[Number]) -> Result<Number, DynError> {
+    values.iter().try_fold(Number::Int(0), |acc, v| acc.add(*v))
+}
+
+fn tally_sum(faces: &[(Number, u64)]) -> Result<Number, DynError> {
+    faces.iter().try_fold(Number::Int(0), |acc, (face, count)| {
+        acc.add(face.mul(Number::Int(*count as i64))?)
+    })
+}
+
+/// Every die of a tally, for functions which need them one by one.
+fn dice(faces: &[(Number, u64)]) -> Vec<Number> {
+    faces
+        .iter()
+        .flat_map(|(face, count)| std::iter::repeat_n(*face, *count as usize))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ints(values: &[i64]) -> Vec<Number> {
+        values.iter().map(|v| Number::Int(*v)).collect()
+    }
+
+    #[test]
+    fn test_filter_pool() {
+        let pool = Value::Pool(ints(&[6, 2, 6, 4]));
+        let filtered = pool.filter(&RelOp::Equals, Number::Int(6)).unwrap();
+
+        assert_eq!(filtered, Value::Pool(ints(&[6, 6])));
+        assert_eq!(filtered.count().unwrap(), 2);
+        assert_eq!(filtered.as_number().unwrap(), Number::Int(12));
+    }
+
+    #[test]
+    fn test_filter_tally() {
+        let tally = Value::Tally(vec![(Number::Int(1), 40_000), (Number::Int(6), 30_000)]);
+        let filtered = tally.filter(&RelOp::Equals, Number::Int(6)).unwrap();
+
+        assert_eq!(tally.count().unwrap(), 70_000);
+        assert_eq!(tally.as_number().unwrap(), Number::Int(220_000));
+        assert_eq!(filtered.count().unwrap(), 30_000);
+        assert_eq!(tally.take(3).unwrap().count().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_empty_pool() {
+        let pool = Value::Pool(vec![]);
+
+        assert!(!pool.as_bool().unwrap());
+        assert_eq!(pool.to_string(), "0");
+    }
+
+    #[test]
+    fn test_group_sets() {
+        let sets = group_sets(&ints(&[8, 4, 8, 1, 4, 8, 9]));
+
+        assert_eq!(
+            sets,
+            vec![
+                (3, Number::Int(8)),
+                (2, Number::Int(4)),
+                (1, Number::Int(9)),
+                (1, Number::Int(1))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_list_is_not_a_number() {
+        let list = Value::List(vec![Value::Number(Number::Int(1))]);
+        assert!(list.as_number().is_err());
+    }
+}
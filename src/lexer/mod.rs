@@ -11,34 +11,24 @@ pub fn tokenize(expression: &str) -> Result<Vec<Token>, DynError> {
             ')' => tokens.push(Token::CloseParen),
             '[' => tokens.push(Token::OpenBracket),
             ']' => tokens.push(Token::CloseBracket),
+            '{' => tokens.push(Token::OpenBrace),
+            '}' => tokens.push(Token::CloseBrace),
             '+' => tokens.push(Token::Add),
-            '-' => {
-                if let Some('0'..='9') = chars.peek() {
-                    println!("negative number");
-                    parse_number(c, &mut chars, &mut tokens)
-                } else {
-                    tokens.push(Token::Sub);
-                }
-            }
+            '-' => tokens.push(Token::Sub),
             '*' => tokens.push(Token::Mul),
             '/' => tokens.push(Token::Div),
             '%' => tokens.push(Token::Mod),
+            '^' => tokens.push(Token::Caret),
             ',' => tokens.push(Token::Comma),
+            ':' => tokens.push(Token::Colon),
             '.' => tokens.push(Token::Dot),
-            'd' => tokens.push(Token::D),
-            'f' => tokens.push(Token::F),
-            'k' => tokens.push(Token::K),
-            'h' => tokens.push(Token::H),
-            'l' => tokens.push(Token::L),
             '!' => tokens.push(Token::Exclamation),
-            'r' => tokens.push(Token::R),
             '=' => tokens.push(Token::Equals),
             '>' => tokens.push(Token::Greater),
             '<' => tokens.push(Token::Less),
             '~' => tokens.push(Token::Tilde),
-            '0'..='9' => {
-                parse_number(c, &mut chars, &mut tokens);
-            }
+            '0'..='9' => parse_number(c, &mut chars, &mut tokens)?,
+            'a'..='z' | 'A'..='Z' | '_' => parse_ident(c, &mut chars, &mut tokens),
             _ => Err(format!("Unexpected character: {}", c))?,
         }
     }
@@ -50,7 +40,7 @@ fn parse_number(
     c: char,
     chars: &mut std::iter::Peekable<std::str::Chars<'_>>,
     tokens: &mut Vec<Token>,
-) {
+) -> Result<(), DynError> {
     let mut value = c.to_string();
 
     while let Some('0'..='9') = chars.peek() {
@@ -61,8 +51,7 @@ fn parse_number(
     let first = clone.next();
     let second = clone.next();
 
-    #[allow(clippy::almost_complete_range)]
-    if let (Some('.'), Some('0'..'9')) = (first, second) {
+    if let (Some('.'), Some('0'..='9')) = (first, second) {
         value.push(chars.next().unwrap());
 
         while let Some('0'..='9') = chars.peek() {
@@ -72,9 +61,27 @@ fn parse_number(
         let parsed = value.parse::<f64>().unwrap();
         tokens.push(Token::Float(parsed));
     } else {
-        let parsed = value.parse::<i64>().unwrap();
+        let parsed = value
+            .parse::<i64>()
+            .map_err(|_| format!("Integer {} is too large", value))?;
         tokens.push(Token::Int(parsed));
     }
+
+    Ok(())
+}
+
+fn parse_ident(
+    c: char,
+    chars: &mut std::iter::Peekable<std::str::Chars<'_>>,
+    tokens: &mut Vec<Token>,
+) {
+    let mut value = c.to_string();
+
+    while let Some('a'..='z' | 'A'..='Z' | '_') = chars.peek() {
+        value.push(chars.next().unwrap());
+    }
+
+    tokens.push(Token::Ident(value));
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -83,31 +90,33 @@ pub enum Token {
     Int(i64),
     Float(f64),
 
+    // Words
+    // Dice notation letters such as `d`, `kh` or `df` are lexed as words too
+    // and split up by the parser, since they can't be told apart from
+    // identifiers without knowing where in an expression they appear.
+    Ident(String),
+
     // Operations
     Add,
     Sub,
     Mul,
     Div,
     Mod,
+    Caret,
 
     // Punctuation
     OpenParen,
     CloseParen,
     OpenBracket,
     CloseBracket,
+    OpenBrace,
+    CloseBrace,
     Comma,
+    Colon,
     Dot,
 
-    // Rolls
-    D, // Also a modifier
-    F, // Fudge
-
     // Modifiers
-    K,           // Keep
-    H,           // Highest
-    L,           // Lowest
     Exclamation, // Explode
-    R,           // Reroll
 
     // Conditions
     Equals,
@@ -1,4 +1,6 @@
+mod analyzer;
 mod cli;
+mod config;
 mod evaluator;
 mod io_utils;
 mod lexer;
@@ -15,6 +17,13 @@ use io_utils::*;
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
+    // evaluations of `--amount` run on rayon's threads, which get as much stack as
+    // the main thread, so the nesting and recursion limits are safe there as well
+    rayon::ThreadPoolBuilder::new()
+        .stack_size(8 << 20)
+        .build_global()
+        .expect("The thread pool is only built once");
+
     let reader = get_reader(cli.source.as_deref())?;
     let writer = get_writer(cli.destination.as_deref())?;
 
@@ -1,38 +1,117 @@
-use crate::lexer::Token;
+use std::collections::VecDeque;
 
-#[derive(Debug)]
+use crate::{lexer::Token, program::DynError};
+
+use super::{nesting_error, MAX_NESTING, MAX_OPERATORS};
+
+/// The tokens left to parse. Peeking borrows and consuming pops from the
+/// front, so parsing stays linear in the number of tokens.
+#[derive(Debug, Clone)]
 pub struct Cursor {
-    tokens: Vec<Token>,
+    tokens: VecDeque<Token>,
+    /// How many levels deep the parser currently is in the expression.
+    nesting: usize,
+    /// How many operators of chains were parsed so far.
+    operators: usize,
 }
 
 impl Cursor {
     pub fn new(tokens: Vec<Token>) -> Self {
-        Self { tokens }
+        Self {
+            tokens: tokens.into(),
+            nesting: 0,
+            operators: 0,
+        }
+    }
+
+    /// Parses with `parse` one level deeper into the expression, failing once
+    /// it is nested deeper than `MAX_NESTING`.
+    pub fn nested<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<T, DynError>,
+    ) -> Result<T, DynError> {
+        if self.nesting >= MAX_NESTING {
+            return Err(nesting_error());
+        }
+
+        self.nesting += 1;
+        let result = parse(self);
+        self.nesting -= 1;
+        result
+    }
+
+    /// Counts an operator of a chain like `1 + 2 + 3`, failing once there are
+    /// more than `MAX_OPERATORS`.
+    pub fn count_operator(&mut self) -> Result<(), DynError> {
+        self.operators += 1;
+
+        if self.operators > MAX_OPERATORS {
+            return Err(format!(
+                "Expression has more than the limit of {} operators",
+                MAX_OPERATORS
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+
+    pub fn first(&self) -> Option<&Token> {
+        self.peek(0)
     }
 
-    pub fn first(&self) -> Option<Token> {
-        self.tokens.clone().into_iter().next()
+    pub fn second(&self) -> Option<&Token> {
+        self.peek(1)
     }
 
-    pub fn second(&self) -> Option<Token> {
-        let mut iter = self.tokens.clone().into_iter();
-        iter.next();
-        iter.next()
+    /// The token `n` tokens ahead, where `peek(0)` is the next token.
+    pub fn peek(&self, n: usize) -> Option<&Token> {
+        self.tokens.get(n)
     }
 
     pub fn expect(&mut self, token: Token) -> Result<Token, String> {
-        match self.tokens.clone().into_iter().next() {
-            Some(t) if t == token => Ok(self.tokens.remove(0)),
+        match self.first() {
+            Some(t) if *t == token => Ok(self.tokens.pop_front().expect("Token was peeked")),
             Some(t) => Err(format!("Expected {:?}, found {:?}", token, t)),
             None => Err(format!("Expected {:?}, found nothing", token)),
         }
     }
 
     pub fn bump(&mut self) -> Option<Token> {
-        if !self.tokens.is_empty() {
-            Some(self.tokens.remove(0))
-        } else {
-            None
+        self.tokens.pop_front()
+    }
+
+    /// Consumes the next token if it is the word `word`.
+    pub fn eat_word(&mut self, word: &str) -> bool {
+        match self.first() {
+            Some(Token::Ident(w)) if w == word => {
+                self.tokens.pop_front();
+                true
+            }
+            _ => false,
         }
     }
+
+    /// Consumes `prefix` from the start of the next word, leaving the rest of
+    /// the word as the next token. Used to split dice notation like `kh` or
+    /// `dl` out of a single word. Dice notation ignores case, so `2D6KH1` works too.
+    pub fn eat_word_prefix(&mut self, prefix: &str) -> bool {
+        match self.tokens.front_mut() {
+            Some(Token::Ident(word)) if starts_with_ignore_case(word, prefix) => {
+                if word.len() == prefix.len() {
+                    self.tokens.pop_front();
+                } else {
+                    word.drain(..prefix.len());
+                }
+
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+pub fn starts_with_ignore_case(word: &str, prefix: &str) -> bool {
+    word.get(..prefix.len())
+        .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
 }
@@ -0,0 +1,371 @@
+use std::fmt::{Display, Formatter, Result};
+
+use super::{Condition, Expr, Link, LogicOp, Modifier, Sides, Statement};
+
+// How tightly each kind of expression binds, mirroring the parse functions.
+// An operand which binds looser than its position allows is wrapped in parens.
+const IF: u8 = 0;
+const OR: u8 = 1;
+const AND: u8 = 2;
+const NOT: u8 = 3;
+const RELATIONAL: u8 = 4;
+const ADDITIVE: u8 = 5;
+const MULTIPLICATIVE: u8 = 6;
+const UNARY: u8 = 7;
+const EXPONENTIAL: u8 = 8;
+const ROLL: u8 = 9;
+const PRIMARY: u8 = 10;
+
+impl Expr {
+    pub(super) fn precedence(&self) -> u8 {
+        match self {
+            Expr::Int(v) if *v < 0 => UNARY,
+            Expr::Float(v) if *v < 0.0 => UNARY,
+            Expr::Int(_)
+            | Expr::Float(_)
+            | Expr::Ident(_)
+            | Expr::Call { .. }
+            | Expr::Table { .. }
+            | Expr::Group { .. } => PRIMARY,
+            Expr::Additive { .. } => ADDITIVE,
+            Expr::Multiplicative { .. } => MULTIPLICATIVE,
+            Expr::Exponential { .. } => EXPONENTIAL,
+            Expr::Negate(_) => UNARY,
+            Expr::Relational { .. } | Expr::Where { .. } => RELATIONAL,
+            Expr::Logical {
+                operator: LogicOp::Or,
+                ..
+            } => OR,
+            Expr::Logical {
+                operator: LogicOp::And,
+                ..
+            } => AND,
+            Expr::Not(_) => NOT,
+            // the condition of a trailing reroll or explode reads as much as it can,
+            // like the branches of an `if`, so anything after it must be kept out with parens
+            Expr::Roll { modifiers, .. }
+                if modifiers.last().and_then(Modifier::condition).is_some() =>
+            {
+                IF
+            }
+            Expr::Repeat { .. } | Expr::Roll { .. } | Expr::Draw { .. } => ROLL,
+            Expr::If { .. } => IF,
+        }
+    }
+}
+
+/// Writes `expr`, in parens if it binds looser than `precedence`.
+fn write_operand(f: &mut Formatter<'_>, expr: &Expr, precedence: u8) -> Result {
+    if expr.precedence() < precedence {
+        write!(f, "({})", expr)
+    } else {
+        write!(f, "{}", expr)
+    }
+}
+
+/// Writes the count in front of a `d` or `x`. A name needs a space, since
+/// `nd6` would be read as the single word `nd`.
+fn write_count(f: &mut Formatter<'_>, count: &Expr) -> Result {
+    match count {
+        Expr::Ident(name) => write!(f, "{} ", name),
+        count => write_operand(f, count, PRIMARY),
+    }
+}
+
+/// Writes the amount of a modifier, which is either a plain integer or in parens.
+fn write_amount(f: &mut Formatter<'_>, amount: &Expr) -> Result {
+    match amount {
+        Expr::Int(v) if *v >= 0 => write!(f, "{}", v),
+        amount => write!(f, "({})", amount),
+    }
+}
+
+fn write_list(f: &mut Formatter<'_>, exprs: &[Expr]) -> Result {
+    for (i, expr) in exprs.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+
+        write!(f, "{}", expr)?;
+    }
+
+    Ok(())
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Expr::Int(v) => write!(f, "{}", v),
+            // keep the decimal point, so the float doesn't become an integer when read back
+            Expr::Float(v) if v.fract() == 0.0 => write!(f, "{:.1}", v),
+            Expr::Float(v) => write!(f, "{}", v),
+            Expr::Ident(name) => write!(f, "{}", name),
+            Expr::Call { name, args } => {
+                write!(f, "{}(", name)?;
+                write_list(f, args)?;
+                write!(f, ")")
+            }
+            // left associative, so only the right operands need to bind tighter
+            Expr::Additive { .. } | Expr::Multiplicative { .. } | Expr::Logical { .. } => {
+                let (first, links) = self.chain();
+                write_operand(f, first, self.precedence())?;
+
+                for link in links {
+                    match link {
+                        Link::Additive(operator, right) | Link::Multiplicative(operator, right) => {
+                            write!(f, " {} ", operator)?;
+                            write_operand(f, right, self.precedence() + 1)?;
+                        }
+                        Link::Logical(operator, right) => {
+                            write!(f, " {} ", operator)?;
+                            write_operand(f, right, self.precedence() + 1)?;
+                        }
+                    }
+                }
+
+                Ok(())
+            }
+            Expr::Exponential {
+                left,
+                operator,
+                right,
+            } => {
+                write_operand(f, left, ROLL)?;
+                write!(f, " {} ", operator)?;
+                write_operand(f, right, UNARY)
+            }
+            Expr::Negate(expr) => {
+                write!(f, "-")?;
+                write_operand(f, expr, UNARY)
+            }
+            Expr::Relational {
+                left,
+                operator,
+                right,
+            } => {
+                write_operand(f, left, ADDITIVE)?;
+                write!(f, " {} ", operator)?;
+                write_operand(f, right, ADDITIVE)
+            }
+            Expr::Not(expr) => {
+                write!(f, "not ")?;
+                write_operand(f, expr, NOT)
+            }
+            Expr::Repeat { count, expr } => {
+                write_count(f, count)?;
+                write!(f, "x({})", expr)
+            }
+            Expr::Table { name, roll } => match roll {
+                Some(roll) => write!(f, "table({}, {})", name, roll),
+                None => write!(f, "table({})", name),
+            },
+            Expr::Where { expr, condition } => {
+                write_operand(f, expr, ADDITIVE)?;
+                write!(f, " where {}", condition)
+            }
+            Expr::If {
+                condition,
+                then,
+                otherwise,
+            } => write!(f, "if {} then {} else {}", condition, then, otherwise),
+            Expr::Roll {
+                rolls,
+                sides,
+                modifiers,
+            } => {
+                write_count(f, rolls)?;
+                write!(f, "d{}", sides)?;
+                write_modifiers(f, modifiers)
+            }
+            Expr::Draw { count, sides } => {
+                write_operand(f, count, PRIMARY)?;
+                write!(f, " draw d{}", sides)
+            }
+            Expr::Group { exprs, modifiers } => {
+                write!(f, "{{")?;
+                write_list(f, exprs)?;
+                write!(f, "}}")?;
+                write_modifiers(f, modifiers)
+            }
+        }
+    }
+}
+
+fn write_modifiers(f: &mut Formatter<'_>, modifiers: &[Modifier]) -> Result {
+    for (i, modifier) in modifiers.iter().enumerate() {
+        // a condition ending in a name would run into a modifier like `kh`
+        if i > 0
+            && modifiers[i - 1].condition().is_some()
+            && !matches!(modifier, Modifier::Explode { .. })
+        {
+            write!(f, " ")?;
+        }
+
+        write!(f, "{}", modifier)?;
+    }
+
+    Ok(())
+}
+
+impl Display for Sides {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Sides::Expr(expr) => match expr.as_ref() {
+                Expr::Int(v) if *v >= 0 => write!(f, "{}", v),
+                expr => write!(f, "({})", expr),
+            },
+            Sides::Range { min, max } => write!(f, "[{}..{}]", min, max),
+            Sides::Values(values) => {
+                write!(f, "[")?;
+                write_list(f, values)?;
+                write!(f, "]")
+            }
+            Sides::Weighted(weights) => {
+                let weights = weights
+                    .iter()
+                    .map(|(value, weight)| format!("{}: {}", value, weight))
+                    .collect::<Vec<_>>();
+
+                write!(f, "{{{}}}", weights.join(", "))
+            }
+            Sides::Fudge => write!(f, "f"),
+            Sides::Percentile => write!(f, "%"),
+        }
+    }
+}
+
+impl Modifier {
+    fn condition(&self) -> Option<&Condition> {
+        match self {
+            Modifier::Reroll { condition, .. }
+            | Modifier::Explode { condition, .. }
+            | Modifier::CriticalSuccess(condition)
+            | Modifier::CriticalFailure(condition) => condition.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Modifier {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let (symbol, amount) = match self {
+            Modifier::KeepHighest(amount) => ("kh", Some(amount)),
+            Modifier::KeepLowest(amount) => ("kl", Some(amount)),
+            Modifier::DropHighest(amount) => ("dh", Some(amount)),
+            Modifier::DropLowest(amount) => ("dl", Some(amount)),
+            Modifier::Reroll { amount, .. } => ("r", Some(amount)),
+            Modifier::Explode {
+                amount, compound, ..
+            } => (if *compound { "!!" } else { "!" }, amount.as_ref()),
+            Modifier::Unique => ("u", None),
+            Modifier::SortAscending => ("sa", None),
+            Modifier::SortDescending => ("sd", None),
+            Modifier::Min(amount) => ("min", Some(amount)),
+            Modifier::Max(amount) => ("max", Some(amount)),
+            Modifier::Bonus(amount) => ("b", Some(amount)),
+            Modifier::Penalty(amount) => ("p", Some(amount)),
+            Modifier::CriticalSuccess(_) => ("cs", None),
+            Modifier::CriticalFailure(_) => ("cf", None),
+        };
+
+        write!(f, "{}", symbol)?;
+
+        if let Some(amount) = amount {
+            write_amount(f, amount)?;
+        }
+
+        match self.condition() {
+            Some(condition) => write!(f, "{}", condition),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}", self.operator)?;
+        write_operand(f, &self.value, ADDITIVE)
+    }
+}
+
+impl Display for Statement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Statement::Definition { name, params, body } if params.is_empty() => {
+                write!(f, "{} = {}", name, body)
+            }
+            Statement::Definition { name, params, body } => {
+                write!(f, "{}({}) = {}", name, params.join(", "), body)
+            }
+            Statement::Expr(expr) => write!(f, "{}", expr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        lexer::tokenize,
+        parser::{parse_statement, Cursor},
+    };
+
+    fn format(source: &str) -> String {
+        let mut cursor = Cursor::new(tokenize(source).unwrap());
+        parse_statement(&mut cursor).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_canonical_form() {
+        let cases = [
+            ("2d6kh!>=5", "2d6kh1!>=5"),
+            ("2d6!2", "2d6!2"),
+            ("d20 + 5", "1d20 + 5"),
+            ("4d6d", "4d6dl1"),
+            ("(1d4)d(2+2)", "(1d4)d(2 + 2)"),
+            ("1 - (2 - 3)", "1 - (2 - 3)"),
+            ("(1 - 2) - 3", "1 - 2 - 3"),
+            ("-2^2", "-2 ^ 2"),
+            ("(-2)^2", "(-2) ^ 2"),
+            ("2^3^2", "2 ^ 3 ^ 2"),
+            ("(2^3)^2", "(2 ^ 3) ^ 2"),
+            ("repeat(6, 4d6dl)", "6x(4d6dl1)"),
+            ("d[1, 2, 3] + d[1..4] + 2df", "1d[1, 2, 3] + 1d[1..4] + 2df"),
+            ("table(loot,1d20+2)", "table(loot, 1d20 + 2)"),
+            ("table(loot)*2", "table(loot) * 2"),
+            ("d{1:1, 6:2*2}", "1d{1: 1, 6: 2 * 2}"),
+            ("(8d6) where >3", "8d6 where >3"),
+            (
+                "if 1d20>=15 then 2d6 else 0",
+                "if 1d20 >= 15 then 2d6 else 0",
+            ),
+            ("not (1 = 2 or 2 = 2)", "not (1 = 2 or 2 = 2)"),
+            ("dmg(n,b)=n d6+b", "dmg(n, b) = n d6 + b"),
+            ("1d6r1<x kh1", "1d6r1<x kh1"),
+            ("2.0 * 1.5", "2.0 * 1.5"),
+            ("(1d6!>3) * 2", "(1d6!>3) * 2"),
+            ("1d6!>3 * 2", "1d6!>3 * 2"),
+            ("4D6KH3 + dF", "4d6kh3 + 1df"),
+            ("2d%", "2d%"),
+            ("d%b + d100P2", "1d%b1 + 1d100p2"),
+            ("4d6min2max(x)", "4d6min2max(x)"),
+            ("4d6s + 4d6sdkh3", "4d6sa + 4d6sdkh3"),
+            ("5d10U", "5d10u"),
+            (
+                "3 draw d[1..52] + draw d[1, 1, 2]",
+                "3 draw d[1..52] + 1 draw d[1, 1, 2]",
+            ),
+            ("(n+1) draw d52", "(n + 1) draw d52"),
+            ("1d10!!>8", "1d10!!>8"),
+            ("2d6!!3 + 2d6!", "2d6!!3 + 2d6!"),
+            ("2d6ro<2", "2d6r1<2"),
+            ("1d20cs20cf<3", "1d20cs=20 cf<3"),
+            ("{4d6 + 3d8, 5d10}kh1 + 2", "{4d6 + 3d8, 5d10}kh1 + 2"),
+        ];
+
+        for (source, expected) in cases {
+            let formatted = format(source);
+            assert_eq!(formatted, expected, "formatting {}", source);
+            assert_eq!(format(&formatted), formatted, "reparsing {}", formatted);
+        }
+    }
+}
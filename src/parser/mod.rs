@@ -1,134 +1,508 @@
 mod cursor;
+mod display;
 mod roll_parser;
 
 pub use cursor::Cursor;
 
+use cursor::starts_with_ignore_case;
+
 use std::fmt::Display;
 
 use crate::{lexer::Token, program::DynError};
 
+/// How deeply an expression may be nested in parens, unary operators, calls and
+/// the like. Parsing, analyzing and evaluating recurse once per level, so this
+/// keeps deep expressions from overflowing the stack. Chains like `1 + 2 + 3`
+/// are walked in a loop, so they only count towards `MAX_OPERATORS`.
+pub const MAX_NESTING: usize = 256;
+
+/// How many operators of chains like `1 + 2 + 3` an expression may have. Chains
+/// are parsed, analyzed and evaluated in a loop, but a tree is still freed by
+/// recursing once per operator, so this is far above anything written by hand
+/// and well below what overflows the stack.
+pub const MAX_OPERATORS: usize = 50_000;
+
+/// The error for an expression nested deeper than `MAX_NESTING`.
+pub fn nesting_error() -> DynError {
+    format!(
+        "Expression is nested deeper than the limit of {} levels",
+        MAX_NESTING
+    )
+    .into()
+}
+
+/// Runs `f` with as much stack as the main thread has, which `MAX_NESTING` is
+/// meant for, instead of the smaller stack of test threads.
+#[cfg(test)]
+pub fn on_main_stack<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    std::thread::Builder::new()
+        .stack_size(8 << 20)
+        .spawn(f)
+        .unwrap()
+        .join()
+        .unwrap()
+}
+
 pub fn parse(cursor: &mut Cursor) -> Result<Expr, DynError> {
-    parse_expr(cursor)
+    let expr = parse_expr(cursor)?;
+
+    match cursor.first() {
+        Some(token) => Err(format!("Unexpected token {:?}", token).into()),
+        None => Ok(expr),
+    }
+}
+
+/// Parses a line that is either a definition, like `adv(x) = max(1d20, 1d20) + x`,
+/// or an expression to evaluate.
+pub fn parse_statement(cursor: &mut Cursor) -> Result<Statement, DynError> {
+    if starts_with_definition(cursor) {
+        let (name, params) = parse_signature(cursor)?;
+        cursor.expect(Token::Equals)?;
+
+        let body = parse(cursor)?;
+        return Ok(Statement::Definition { name, params, body });
+    }
+
+    Ok(Statement::Expr(parse(cursor)?))
+}
+
+/// Whether the tokens start with a signature followed by `=`, without consuming them.
+fn starts_with_definition(cursor: &Cursor) -> bool {
+    if !matches!(cursor.first(), Some(Token::Ident(_))) {
+        return false;
+    }
+
+    let mut n = 1;
+
+    if cursor.peek(n) == Some(&Token::OpenParen) {
+        n += 1;
+
+        while matches!(cursor.peek(n), Some(Token::Ident(_) | Token::Comma)) {
+            n += 1;
+        }
+
+        if cursor.peek(n) != Some(&Token::CloseParen) {
+            return false;
+        }
+
+        n += 1;
+    }
+
+    cursor.peek(n) == Some(&Token::Equals)
+}
+
+/// Parses the name and parameter list of a definition, like `dmg(n, b)` or `attack`.
+pub fn parse_signature(cursor: &mut Cursor) -> Result<(String, Vec<String>), DynError> {
+    let name = parse_name(cursor)?;
+    let mut params = vec![];
+
+    if cursor.first() == Some(&Token::OpenParen) {
+        cursor.bump();
+
+        if cursor.first() != Some(&Token::CloseParen) {
+            params.push(parse_name(cursor)?);
+
+            while cursor.first() == Some(&Token::Comma) {
+                cursor.bump();
+                params.push(parse_name(cursor)?);
+            }
+        }
+
+        cursor.expect(Token::CloseParen)?;
+    }
+
+    Ok((name, params))
+}
+
+fn parse_name(cursor: &mut Cursor) -> Result<String, DynError> {
+    match cursor.bump() {
+        Some(Token::Ident(name)) if is_dice_word(&name) => {
+            Err(format!("'{}' is dice notation, not a name", name).into())
+        }
+        Some(Token::Ident(name)) if is_keyword(&name) => {
+            Err(format!("'{}' is a keyword, not a name", name).into())
+        }
+        Some(Token::Ident(name)) => Ok(name),
+        Some(token) => Err(format!("Expected a name, found {:?}", token).into()),
+        None => Err("Expected a name, found nothing".into()),
+    }
 }
 
 fn parse_expr(cursor: &mut Cursor) -> Result<Expr, DynError> {
-    parse_additive(cursor)
+    cursor.nested(|cursor| {
+        if cursor.eat_word("if") {
+            return parse_if(cursor);
+        }
+
+        parse_or(cursor)
+    })
+}
+
+fn parse_if(cursor: &mut Cursor) -> Result<Expr, DynError> {
+    let condition = parse_expr(cursor)?;
+
+    if !cursor.eat_word("then") {
+        return Err("Expected 'then' after the condition of 'if'".into());
+    }
+
+    let then = parse_expr(cursor)?;
+
+    if !cursor.eat_word("else") {
+        return Err("Expected 'else' after 'if ... then ...'".into());
+    }
+
+    let otherwise = parse_expr(cursor)?;
+
+    Ok(Expr::If {
+        condition: Box::new(condition),
+        then: Box::new(then),
+        otherwise: Box::new(otherwise),
+    })
+}
+
+fn parse_or(cursor: &mut Cursor) -> Result<Expr, DynError> {
+    let mut expr = parse_and(cursor)?;
+
+    while cursor.eat_word("or") {
+        cursor.count_operator()?;
+
+        let right = parse_and(cursor)?;
+
+        expr = Expr::Logical {
+            left: Box::new(expr),
+            operator: LogicOp::Or,
+            right: Box::new(right),
+        };
+    }
+
+    Ok(expr)
+}
+
+fn parse_and(cursor: &mut Cursor) -> Result<Expr, DynError> {
+    let mut expr = parse_not(cursor)?;
+
+    while cursor.eat_word("and") {
+        cursor.count_operator()?;
+
+        let right = parse_not(cursor)?;
+
+        expr = Expr::Logical {
+            left: Box::new(expr),
+            operator: LogicOp::And,
+            right: Box::new(right),
+        };
+    }
+
+    Ok(expr)
+}
+
+fn parse_not(cursor: &mut Cursor) -> Result<Expr, DynError> {
+    if cursor.eat_word("not") {
+        return Ok(Expr::Not(Box::new(cursor.nested(parse_not)?)));
+    }
+
+    parse_relational(cursor)
+}
+
+fn parse_relational(cursor: &mut Cursor) -> Result<Expr, DynError> {
+    let expr = parse_additive(cursor)?;
+
+    if cursor.eat_word("where") {
+        let operator = parse_rel_op(cursor).ok_or("Expected a comparison after 'where'")?;
+        let value = parse_additive(cursor)?;
+
+        return Ok(Expr::Where {
+            expr: Box::new(expr),
+            condition: Condition::new(operator, value),
+        });
+    }
+
+    match parse_rel_op(cursor) {
+        Some(operator) => {
+            let right = parse_additive(cursor)?;
+
+            Ok(Expr::Relational {
+                left: Box::new(expr),
+                operator,
+                right: Box::new(right),
+            })
+        }
+        None => Ok(expr),
+    }
+}
+
+fn parse_rel_op(cursor: &mut Cursor) -> Option<RelOp> {
+    let operator = match (cursor.first(), cursor.second()) {
+        (Some(Token::Less), Some(Token::Equals)) => {
+            cursor.bump();
+            RelOp::LessEqual
+        }
+        (Some(Token::Greater), Some(Token::Equals)) => {
+            cursor.bump();
+            RelOp::GreaterEqual
+        }
+        (Some(Token::Tilde), Some(Token::Equals)) => {
+            cursor.bump();
+            RelOp::NotEquals
+        }
+        (Some(Token::Less), _) => RelOp::Less,
+        (Some(Token::Greater), _) => RelOp::Greater,
+        (Some(Token::Equals), _) => RelOp::Equals,
+        _ => return None,
+    };
+
+    cursor.bump();
+    Some(operator)
 }
 
 fn parse_additive(cursor: &mut Cursor) -> Result<Expr, DynError> {
     let mut expr = parse_multiplicative(cursor)?;
 
-    while let Some(token) = cursor.first() {
-        match token {
-            Token::Add => {
-                cursor.bump();
-                let right = parse_multiplicative(cursor)?;
+    while matches!(cursor.first(), Some(Token::Add | Token::Sub)) {
+        cursor.count_operator()?;
 
-                expr = Expr::Additive {
-                    left: Box::new(expr),
-                    operator: BinOp::Add,
-                    right: Box::new(right),
-                };
-            }
-            Token::Sub => {
-                cursor.bump();
-                let right = parse_multiplicative(cursor)?;
+        let operator = match cursor.bump() {
+            Some(Token::Add) => BinOp::Add,
+            _ => BinOp::Sub,
+        };
 
-                expr = Expr::Additive {
-                    left: Box::new(expr),
-                    operator: BinOp::Sub,
-                    right: Box::new(right),
-                };
-            }
-            _ => break,
-        }
+        let right = parse_multiplicative(cursor)?;
+
+        expr = Expr::Additive {
+            left: Box::new(expr),
+            operator,
+            right: Box::new(right),
+        };
     }
 
     Ok(expr)
 }
 
 fn parse_multiplicative(cursor: &mut Cursor) -> Result<Expr, DynError> {
-    let mut expr = parse_roll(cursor)?;
+    let mut expr = parse_unary(cursor)?;
 
-    while let Some(token) = cursor.first() {
-        match token {
-            Token::Mul => {
-                cursor.bump();
-                let right = parse_roll(cursor)?;
+    while matches!(cursor.first(), Some(Token::Mul | Token::Div | Token::Mod)) {
+        cursor.count_operator()?;
 
-                expr = Expr::Multiplicative {
-                    left: Box::new(expr),
-                    operator: BinOp::Mul,
-                    right: Box::new(right),
-                };
-            }
-            Token::Div => {
+        let operator = match cursor.bump() {
+            Some(Token::Mul) => BinOp::Mul,
+            // `//` is integer division
+            Some(Token::Div) if cursor.first() == Some(&Token::Div) => {
                 cursor.bump();
-                let right = parse_roll(cursor)?;
-
-                expr = Expr::Multiplicative {
-                    left: Box::new(expr),
-                    operator: BinOp::Div,
-                    right: Box::new(right),
-                };
+                BinOp::IntDiv
             }
-            Token::Mod => {
-                cursor.bump();
-                let right = parse_roll(cursor)?;
+            Some(Token::Div) => BinOp::Div,
+            _ => BinOp::Mod,
+        };
 
-                expr = Expr::Multiplicative {
-                    left: Box::new(expr),
-                    operator: BinOp::Mod,
-                    right: Box::new(right),
-                };
-            }
-            _ => break,
-        }
+        let right = parse_unary(cursor)?;
+
+        expr = Expr::Multiplicative {
+            left: Box::new(expr),
+            operator,
+            right: Box::new(right),
+        };
     }
 
     Ok(expr)
 }
 
-fn parse_roll(cursor: &mut Cursor) -> Result<Expr, DynError> {
-    let rolls = if cursor.first() != Some(Token::D) {
-        Some(parse_primary(cursor)?)
-    } else {
-        None
-    };
-
-    let rolls = match (rolls, cursor.first()) {
-        (Some(Expr::Float(v)), _) => return Ok(Expr::Float(v)),
-        (Some(t), Some(Token::D)) => {
+fn parse_unary(cursor: &mut Cursor) -> Result<Expr, DynError> {
+    match cursor.first() {
+        Some(Token::Sub) => {
             cursor.bump();
-            t
+            Ok(Expr::Negate(Box::new(cursor.nested(parse_unary)?)))
         }
-        (Some(t), _) => return Ok(t),
-        (None, _) => {
+        Some(Token::Add) => {
             cursor.bump();
-            Expr::Int(1)
+            cursor.nested(parse_unary)
+        }
+        _ => parse_exponential(cursor),
+    }
+}
+
+/// `^` is right associative and binds tighter than unary minus, so `-2^2` is -4
+/// and `2^3^2` is 2^9.
+fn parse_exponential(cursor: &mut Cursor) -> Result<Expr, DynError> {
+    let expr = parse_roll(cursor)?;
+
+    if cursor.first() == Some(&Token::Caret) {
+        cursor.bump();
+        let right = cursor.nested(parse_unary)?;
+
+        return Ok(Expr::Exponential {
+            left: Box::new(expr),
+            operator: BinOp::Pow,
+            right: Box::new(right),
+        });
+    }
+
+    Ok(expr)
+}
+
+fn parse_roll(cursor: &mut Cursor) -> Result<Expr, DynError> {
+    if cursor.eat_word("draw") {
+        return parse_draw(Expr::Int(1), cursor);
+    }
+
+    let rolls = if starts_with_dice(cursor) {
+        cursor.eat_word_prefix("d");
+        Expr::Int(1)
+    } else {
+        match parse_primary(cursor)? {
+            Expr::Float(v) => return Ok(Expr::Float(v)),
+            count if cursor.eat_word("x") => {
+                return Ok(Expr::Repeat {
+                    count: Box::new(count),
+                    expr: Box::new(cursor.nested(parse_roll)?),
+                });
+            }
+            count if cursor.eat_word("draw") => return parse_draw(count, cursor),
+            rolls if cursor.eat_word_prefix("d") => rolls,
+            expr => return Ok(expr),
         }
     };
 
     roll_parser::parse(rolls, cursor)
 }
 
+/// Parses the deck after `draw`, which is written like a die.
+fn parse_draw(count: Expr, cursor: &mut Cursor) -> Result<Expr, DynError> {
+    if !cursor.eat_word_prefix("d") {
+        return Err("Expected a deck like `d[1..52]` after 'draw'".into());
+    }
+
+    Ok(Expr::Draw {
+        count: Box::new(count),
+        sides: roll_parser::parse_sides(cursor)?,
+    })
+}
+
+/// Whether the next word is a roll with an implicit count, like `d6` or `df`,
+/// rather than an identifier.
+fn starts_with_dice(cursor: &Cursor) -> bool {
+    matches!(cursor.first(), Some(Token::Ident(word)) if is_dice_word(word))
+}
+
+fn is_dice_word(word: &str) -> bool {
+    word.eq_ignore_ascii_case("d") || starts_with_ignore_case(word, "df")
+}
+
+fn is_keyword(word: &str) -> bool {
+    matches!(
+        word,
+        "if" | "then" | "else" | "and" | "or" | "not" | "where" | "draw"
+    )
+}
+
 fn parse_primary(cursor: &mut Cursor) -> Result<Expr, DynError> {
     match cursor.bump() {
         Some(Token::Int(value)) => Ok(Expr::Int(value)),
         Some(Token::Float(value)) => Ok(Expr::Float(value)),
+        Some(Token::Ident(name)) if cursor.first() == Some(&Token::OpenParen) => {
+            cursor.bump();
+            let mut args = vec![];
+
+            if cursor.first() != Some(&Token::CloseParen) {
+                args.push(parse_expr(cursor)?);
+
+                while cursor.first() == Some(&Token::Comma) {
+                    cursor.bump();
+                    args.push(parse_expr(cursor)?);
+                }
+            }
+
+            cursor.expect(Token::CloseParen)?;
+
+            // repeat evaluates its second argument once per repetition, so it
+            // can't be an ordinary call with already evaluated arguments
+            if name == "repeat" {
+                let [count, expr] = <[Expr; 2]>::try_from(args)
+                    .map_err(|_| "'repeat' expects a count and an expression")?;
+
+                return Ok(Expr::Repeat {
+                    count: Box::new(count),
+                    expr: Box::new(expr),
+                });
+            }
+
+            // the first argument of table names a table, which isn't a value
+            if name == "table" {
+                return parse_table(args);
+            }
+
+            Ok(Expr::Call { name, args })
+        }
+        Some(Token::Ident(name)) if is_keyword(&name) => {
+            Err(format!("Unexpected keyword '{}'", name).into())
+        }
+        Some(Token::Ident(name)) => Ok(Expr::Ident(name)),
         Some(Token::OpenParen) => {
             let expr = parse_expr(cursor)?;
             cursor.expect(Token::CloseParen)?;
             Ok(expr)
         }
+        Some(Token::OpenBrace) => parse_group(cursor),
         _ => Err("Expected primary expression".into()),
     }
 }
 
+/// Parses a group like `{4d6 + 3d8, 5d10}kh1`, after its opening brace.
+fn parse_group(cursor: &mut Cursor) -> Result<Expr, DynError> {
+    let mut exprs = vec![parse_expr(cursor)?];
+
+    while cursor.first() == Some(&Token::Comma) {
+        cursor.bump();
+        exprs.push(parse_expr(cursor)?);
+    }
+
+    cursor.expect(Token::CloseBrace)?;
+
+    let modifiers = roll_parser::parse_modifiers(cursor)?;
+
+    if let Some(modifier) = modifiers.iter().find(|m| !m.is_keep_or_drop()) {
+        return Err(format!("Cannot use `{}` on a group, only keep and drop", modifier).into());
+    }
+
+    Ok(Expr::Group { exprs, modifiers })
+}
+
+#[derive(Debug, Clone)]
+pub enum Statement {
+    Definition {
+        name: String,
+        params: Vec<String>,
+        body: Expr,
+    },
+    Expr(Expr),
+}
+
+/// Builds `table(name)` or `table(name, roll)` from the arguments of the call.
+fn parse_table(args: Vec<Expr>) -> Result<Expr, DynError> {
+    let mut args = args.into_iter();
+
+    let Some(Expr::Ident(name)) = args.next() else {
+        return Err("'table' expects the name of a table".into());
+    };
+
+    let roll = args.next().map(Box::new);
+
+    if args.next().is_some() {
+        return Err("'table' expects a table and an optional roll".into());
+    }
+
+    Ok(Expr::Table { name, roll })
+}
+
 #[derive(Debug, Clone)]
 pub enum Expr {
     Int(i64),
     Float(f64),
+    Ident(String),
+    Call {
+        name: String,
+        args: Vec<Expr>,
+    },
     Additive {
         left: Box<Expr>,
         operator: BinOp,
@@ -139,11 +513,150 @@ pub enum Expr {
         operator: BinOp,
         right: Box<Expr>,
     },
+    Exponential {
+        left: Box<Expr>,
+        operator: BinOp,
+        right: Box<Expr>,
+    },
+    Negate(Box<Expr>),
+    Relational {
+        left: Box<Expr>,
+        operator: RelOp,
+        right: Box<Expr>,
+    },
+    Logical {
+        left: Box<Expr>,
+        operator: LogicOp,
+        right: Box<Expr>,
+    },
+    Not(Box<Expr>),
+    Repeat {
+        count: Box<Expr>,
+        expr: Box<Expr>,
+    },
+    Where {
+        expr: Box<Expr>,
+        condition: Condition,
+    },
+    If {
+        condition: Box<Expr>,
+        then: Box<Expr>,
+        otherwise: Box<Expr>,
+    },
     Roll {
         rolls: Box<Expr>,
         sides: Sides,
         modifiers: Vec<Modifier>,
     },
+    /// Expressions whose results are kept or dropped like dice, like `{1d20 + 5, 1d20 + 5}kh1`.
+    Group {
+        exprs: Vec<Expr>,
+        modifiers: Vec<Modifier>,
+    },
+    /// Cards drawn from a deck without putting them back, like `3 draw d[1..52]`.
+    Draw {
+        count: Box<Expr>,
+        sides: Sides,
+    },
+    /// The entry of a random table for a roll, like `table(encounters, 1d6)`.
+    /// Without a roll, the table is rolled on with a die covering all of its rows.
+    Table {
+        name: String,
+        roll: Option<Box<Expr>>,
+    },
+}
+
+/// An operator of a chain like `1 + 2 - 3`, with the operand to its right.
+#[derive(Debug)]
+pub enum Link<E> {
+    Additive(BinOp, E),
+    Multiplicative(BinOp, E),
+    Logical(LogicOp, E),
+}
+
+impl Expr {
+    /// Splits a chain of operators which bind the same, like `1 + 2 - 3`, into its
+    /// first operand and the operators after it. Chains nest on the left, and
+    /// generated expressions can have thousands of operators, so they are walked
+    /// in a loop instead of recursing once per operator. Any other expression is
+    /// a chain without operators.
+    pub fn chain(&self) -> (&Expr, Vec<Link<&Expr>>) {
+        let mut links = vec![];
+        let mut expr = self;
+
+        loop {
+            let (left, link) = match expr {
+                Expr::Additive {
+                    left,
+                    operator,
+                    right,
+                } => (left, Link::Additive(operator.clone(), &**right)),
+                Expr::Multiplicative {
+                    left,
+                    operator,
+                    right,
+                } => (left, Link::Multiplicative(operator.clone(), &**right)),
+                Expr::Logical {
+                    left,
+                    operator,
+                    right,
+                } => (left, Link::Logical(operator.clone(), &**right)),
+                _ => break,
+            };
+
+            links.push(link);
+
+            let ends = left.precedence() != expr.precedence();
+            expr = left;
+
+            if ends {
+                break;
+            }
+        }
+
+        links.reverse();
+        (expr, links)
+    }
+
+    /// Like `chain`, but takes the chain apart.
+    pub fn into_chain(self) -> (Expr, Vec<Link<Expr>>) {
+        let mut links = vec![];
+        let mut expr = self;
+
+        loop {
+            let precedence = expr.precedence();
+
+            let (left, link) = match expr {
+                Expr::Additive {
+                    left,
+                    operator,
+                    right,
+                } => (left, Link::Additive(operator, *right)),
+                Expr::Multiplicative {
+                    left,
+                    operator,
+                    right,
+                } => (left, Link::Multiplicative(operator, *right)),
+                Expr::Logical {
+                    left,
+                    operator,
+                    right,
+                } => (left, Link::Logical(operator, *right)),
+                expr => {
+                    links.reverse();
+                    return (expr, links);
+                }
+            };
+
+            links.push(link);
+            expr = *left;
+
+            if expr.precedence() != precedence {
+                links.reverse();
+                return (expr, links);
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -152,7 +665,9 @@ pub enum BinOp {
     Sub,
     Mul,
     Div,
+    IntDiv,
     Mod,
+    Pow,
 }
 
 impl Display for BinOp {
@@ -162,7 +677,26 @@ impl Display for BinOp {
             BinOp::Sub => "-",
             BinOp::Mul => "*",
             BinOp::Div => "/",
+            BinOp::IntDiv => "//",
             BinOp::Mod => "%",
+            BinOp::Pow => "^",
+        };
+
+        write!(f, "{string_representation}")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum LogicOp {
+    And,
+    Or,
+}
+
+impl Display for LogicOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string_representation = match self {
+            LogicOp::And => "and",
+            LogicOp::Or => "or",
         };
 
         write!(f, "{string_representation}")
@@ -172,9 +706,16 @@ impl Display for BinOp {
 #[derive(Debug, Clone)]
 pub enum Sides {
     Expr(Box<Expr>),
-    Range { min: Box<Expr>, max: Box<Expr> },
+    Range {
+        min: Box<Expr>,
+        max: Box<Expr>,
+    },
     Values(Vec<Expr>),
+    /// `d{1: 1, 6: 4}`, sides with how many times each one is on the die.
+    Weighted(Vec<(Expr, Expr)>),
     Fudge,
+    /// `d%`, the same as `d100`.
+    Percentile,
 }
 
 #[derive(Debug, Clone)]
@@ -187,10 +728,43 @@ pub enum Modifier {
         amount: Box<Expr>,
         condition: Option<Condition>,
     },
+    /// Without an amount, a die explodes until it rolls something which doesn't explode.
+    /// A compounding `!!` adds its explosions into the die instead of listing them.
     Explode {
-        amount: Box<Expr>,
+        amount: Option<Box<Expr>>,
         condition: Option<Condition>,
+        compound: bool,
     },
+    /// Rerolls dice until every die shows a different side.
+    Unique,
+    /// Sorts the dice from lowest to highest, without changing the result.
+    SortAscending,
+    /// Sorts the dice from highest to lowest, without changing the result.
+    SortDescending,
+    /// Raises every die below the amount to the amount.
+    Min(Box<Expr>),
+    /// Lowers every die above the amount to the amount.
+    Max(Box<Expr>),
+    /// Call of Cthulhu bonus dice, extra tens dice of which a percentile die uses the best.
+    Bonus(Box<Expr>),
+    /// Call of Cthulhu penalty dice, extra tens dice of which a percentile die uses the worst.
+    Penalty(Box<Expr>),
+    /// Marks the dice which are a critical success, the highest side without a condition.
+    CriticalSuccess(Option<Condition>),
+    /// Marks the dice which are a critical failure, the lowest side without a condition.
+    CriticalFailure(Option<Condition>),
+}
+
+impl Modifier {
+    pub fn is_keep_or_drop(&self) -> bool {
+        matches!(
+            self,
+            Modifier::KeepHighest(_)
+                | Modifier::KeepLowest(_)
+                | Modifier::DropHighest(_)
+                | Modifier::DropLowest(_)
+        )
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -217,3 +791,70 @@ pub enum RelOp {
     Greater,
     GreaterEqual,
 }
+
+impl Display for RelOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string_representation = match self {
+            RelOp::Equals => "=",
+            RelOp::NotEquals => "~=",
+            RelOp::Less => "<",
+            RelOp::LessEqual => "<=",
+            RelOp::Greater => ">",
+            RelOp::GreaterEqual => ">=",
+        };
+
+        write!(f, "{string_representation}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+
+    fn statement(input: &str) -> Statement {
+        parse_statement(&mut Cursor::new(tokenize(input).unwrap())).unwrap()
+    }
+
+    #[test]
+    fn test_parse_function_definition() {
+        let Statement::Definition { name, params, .. } = statement("dmg(n, b) = n d6 + b") else {
+            panic!("expected a definition");
+        };
+
+        assert_eq!(name, "dmg");
+        assert_eq!(params, vec!["n", "b"]);
+    }
+
+    #[test]
+    fn test_parse_macro_definition() {
+        let Statement::Definition { name, params, .. } = statement("attack = 1d20 + 7") else {
+            panic!("expected a definition");
+        };
+
+        assert_eq!(name, "attack");
+        assert!(params.is_empty());
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        on_main_stack(|| {
+            let parse_str = |input: &str| parse(&mut Cursor::new(tokenize(input).unwrap()));
+
+            // chains are walked in a loop, so they only have a limit of their own
+            assert!(parse_str(&vec!["1d6"; 2000].join(" + ")).is_ok());
+            assert!(parse_str(&vec!["1"; MAX_OPERATORS + 2].join(" * ")).is_err());
+            assert!(parse_str(&format!("{}1{}", "(".repeat(300), ")".repeat(300))).is_err());
+            assert!(parse_str(&"1d6r<".repeat(300)).is_err());
+            assert!(parse_str(&format!("{}1", "-".repeat(300))).is_err());
+        });
+    }
+
+    #[test]
+    fn test_parse_call_is_not_definition() {
+        assert!(matches!(
+            statement("adv(3) + 1"),
+            Statement::Expr(Expr::Additive { .. })
+        ));
+    }
+}
@@ -1,22 +1,33 @@
 use crate::{lexer::Token, program::DynError};
 
-use super::{cursor::Cursor, parse_expr, parse_primary, Condition, Expr, Modifier, RelOp, Sides};
+use super::{
+    cursor::Cursor, parse_additive, parse_expr, parse_primary, parse_rel_op, Condition, Expr,
+    Modifier, RelOp, Sides,
+};
 
 pub fn parse(rolls: Expr, cursor: &mut Cursor) -> Result<Expr, DynError> {
-    let sides = parse_sides(cursor)?;
-    let modifiers = parse_modifiers(cursor)?;
-
-    Ok(Expr::Roll {
-        rolls: Box::new(rolls),
-        sides,
-        modifiers,
+    // the conditions of modifiers can hold rolls of their own, like `1d6r<1d6`
+    cursor.nested(|cursor| {
+        let sides = parse_sides(cursor)?;
+        let modifiers = parse_modifiers(cursor)?;
+
+        Ok(Expr::Roll {
+            rolls: Box::new(rolls),
+            sides,
+            modifiers,
+        })
     })
 }
 
-fn parse_sides(cursor: &mut Cursor) -> Result<Sides, DynError> {
+pub fn parse_sides(cursor: &mut Cursor) -> Result<Sides, DynError> {
+    if cursor.eat_word_prefix("f") {
+        return Ok(Sides::Fudge);
+    }
+
     match cursor.bump() {
         Some(Token::Int(value)) => Ok(Sides::Expr(Box::new(Expr::Int(value)))),
         Some(Token::Float(_)) => Err("Cannot use float for number of sides".into()),
+        Some(Token::Mod) => Ok(Sides::Percentile),
         Some(Token::OpenBracket) => {
             let value = parse_expr(cursor)?;
 
@@ -31,7 +42,7 @@ fn parse_sides(cursor: &mut Cursor) -> Result<Sides, DynError> {
                 Some(Token::Comma) => {
                     let mut values = vec![value];
 
-                    while cursor.first() == Some(Token::Comma) {
+                    while cursor.first() == Some(&Token::Comma) {
                         cursor.bump();
                         values.push(parse_expr(cursor)?);
                     }
@@ -57,149 +68,152 @@ fn parse_sides(cursor: &mut Cursor) -> Result<Sides, DynError> {
                 _ => Err("Expected ',' or '..'".into()),
             }
         }
+        Some(Token::OpenBrace) => {
+            let mut weights = vec![];
+
+            loop {
+                let value = parse_expr(cursor)?;
+                cursor.expect(Token::Colon)?;
+                let weight = parse_expr(cursor)?;
+
+                weights.push((value, weight));
+
+                match cursor.bump() {
+                    Some(Token::Comma) => continue,
+                    Some(Token::CloseBrace) => break,
+                    _ => return Err("Expected ',' or '}'".into()),
+                }
+            }
+
+            Ok(Sides::Weighted(weights))
+        }
         Some(Token::OpenParen) => {
             let sides = Sides::Expr(Box::new(parse_expr(cursor)?));
             cursor.expect(Token::CloseParen)?;
             Ok(sides)
         }
-        Some(Token::F) => Ok(Sides::Fudge),
         _ => Err("Expected sides expression".into()),
     }
 }
 
-fn parse_modifiers(cursor: &mut Cursor) -> Result<Vec<Modifier>, DynError> {
+pub fn parse_modifiers(cursor: &mut Cursor) -> Result<Vec<Modifier>, DynError> {
     let mut modifiers = vec![];
 
     loop {
-        match (cursor.first(), cursor.second()) {
-            (Some(Token::K), Some(Token::L)) => {
-                cursor.bump();
-                cursor.bump();
-
-                let mut amount = Expr::Int(1);
-
-                if matches!(cursor.first(), Some(Token::Int(_) | Token::OpenParen)) {
-                    amount = parse_primary(cursor)?;
-                }
-
-                modifiers.push(Modifier::KeepLowest(Box::new(amount)));
-            }
-            (Some(Token::K), _) => {
-                cursor.bump();
-
-                if cursor.first() == Some(Token::H) {
-                    cursor.bump();
-                }
+        if cursor.eat_word_prefix("kl") {
+            let amount = parse_amount(cursor)?;
+            modifiers.push(Modifier::KeepLowest(Box::new(amount)));
+        } else if cursor.eat_word_prefix("kh") || cursor.eat_word_prefix("k") {
+            let amount = parse_amount(cursor)?;
+            modifiers.push(Modifier::KeepHighest(Box::new(amount)));
+        } else if cursor.eat_word_prefix("dh") {
+            let amount = parse_amount(cursor)?;
+            modifiers.push(Modifier::DropHighest(Box::new(amount)));
+        } else if cursor.eat_word_prefix("dl") || cursor.eat_word_prefix("d") {
+            let amount = parse_amount(cursor)?;
+            modifiers.push(Modifier::DropLowest(Box::new(amount)));
+        } else if cursor.eat_word_prefix("ro") {
+            // Roll20's reroll once, which is what `r` does without an amount
+            let condition = parse_condition(cursor)?;
+
+            modifiers.push(Modifier::Reroll {
+                amount: Box::new(Expr::Int(1)),
+                condition,
+            });
+        } else if cursor.eat_word_prefix("r") {
+            let amount = parse_amount(cursor)?;
+            let condition = parse_condition(cursor)?;
+
+            modifiers.push(Modifier::Reroll {
+                amount: Box::new(amount),
+                condition,
+            });
+        } else if cursor.eat_word_prefix("u") {
+            modifiers.push(Modifier::Unique);
+        } else if cursor.eat_word_prefix("sd") {
+            modifiers.push(Modifier::SortDescending);
+        } else if cursor.eat_word_prefix("sa") || cursor.eat_word_prefix("s") {
+            modifiers.push(Modifier::SortAscending);
+        } else if cursor.eat_word_prefix("min") {
+            modifiers.push(Modifier::Min(Box::new(parse_clamp(cursor, "min")?)));
+        } else if cursor.eat_word_prefix("max") {
+            modifiers.push(Modifier::Max(Box::new(parse_clamp(cursor, "max")?)));
+        } else if cursor.eat_word_prefix("b") {
+            let amount = parse_amount(cursor)?;
+            modifiers.push(Modifier::Bonus(Box::new(amount)));
+        } else if cursor.eat_word_prefix("p") {
+            let amount = parse_amount(cursor)?;
+            modifiers.push(Modifier::Penalty(Box::new(amount)));
+        } else if cursor.eat_word_prefix("cs") {
+            modifiers.push(Modifier::CriticalSuccess(parse_critical(cursor)?));
+        } else if cursor.eat_word_prefix("cf") {
+            modifiers.push(Modifier::CriticalFailure(parse_critical(cursor)?));
+        } else if cursor.first() == Some(&Token::Exclamation) {
+            cursor.bump();
 
-                let mut amount = Expr::Int(1);
+            // the compounding `!!` of Roll20 and Foundry
+            let compound = cursor.first() == Some(&Token::Exclamation);
 
-                if matches!(cursor.first(), Some(Token::Int(_) | Token::OpenParen)) {
-                    amount = parse_primary(cursor)?;
-                }
-
-                modifiers.push(Modifier::KeepHighest(Box::new(amount)));
-            }
-            (Some(Token::D), Some(Token::H)) => {
+            if compound {
                 cursor.bump();
-                cursor.bump();
-
-                let mut amount = Expr::Int(1);
-
-                if matches!(cursor.first(), Some(Token::Int(_) | Token::OpenParen)) {
-                    amount = parse_primary(cursor)?;
-                }
-
-                modifiers.push(Modifier::DropHighest(Box::new(amount)));
             }
-            (Some(Token::D), _) => {
-                cursor.bump();
-
-                if cursor.first() == Some(Token::L) {
-                    cursor.bump();
-                }
-
-                let mut amount = Expr::Int(1);
-
-                if matches!(cursor.first(), Some(Token::Int(_) | Token::OpenParen)) {
-                    amount = parse_primary(cursor)?;
-                }
-
-                modifiers.push(Modifier::DropLowest(Box::new(amount)));
-            }
-            (Some(Token::R), _) => {
-                cursor.bump();
 
-                let mut amount = Expr::Int(1);
-
-                if matches!(cursor.first(), Some(Token::Int(_) | Token::OpenParen)) {
-                    amount = parse_primary(cursor)?;
-                }
-
-                let condition = parse_condition(cursor)?;
+            // without an amount, dice explode until they don't
+            let amount = match starts_amount(cursor) {
+                true => Some(Box::new(parse_primary(cursor)?)),
+                false => None,
+            };
+            let condition = parse_condition(cursor)?;
+
+            modifiers.push(Modifier::Explode {
+                amount,
+                condition,
+                compound,
+            });
+        } else {
+            break;
+        }
+    }
 
-                modifiers.push(Modifier::Reroll {
-                    amount: Box::new(amount),
-                    condition,
-                });
-            }
-            (Some(Token::Exclamation), _) => {
-                cursor.bump();
+    Ok(modifiers)
+}
 
-                let mut amount = Expr::Int(1);
+/// Parses the condition of `cs` or `cf`, where a plain number like `cs20` means `cs=20`.
+fn parse_critical(cursor: &mut Cursor) -> Result<Option<Condition>, DynError> {
+    if starts_amount(cursor) {
+        let value = parse_primary(cursor)?;
+        return Ok(Some(Condition::new(RelOp::Equals, value)));
+    }
 
-                if matches!(cursor.first(), Some(Token::Int(_) | Token::OpenParen)) {
-                    amount = parse_primary(cursor)?;
-                }
+    parse_condition(cursor)
+}
 
-                let condition = parse_condition(cursor)?;
+/// Parses the value of `min` or `max`, which can't be left out.
+fn parse_clamp(cursor: &mut Cursor, name: &str) -> Result<Expr, DynError> {
+    match starts_amount(cursor) {
+        true => parse_primary(cursor),
+        false => Err(format!("Expected a value after '{}'", name).into()),
+    }
+}
 
-                modifiers.push(Modifier::Explode {
-                    amount: Box::new(amount),
-                    condition,
-                });
-            }
-            _ => break,
-        }
+fn parse_amount(cursor: &mut Cursor) -> Result<Expr, DynError> {
+    if starts_amount(cursor) {
+        parse_primary(cursor)
+    } else {
+        Ok(Expr::Int(1))
     }
+}
 
-    Ok(modifiers)
+fn starts_amount(cursor: &Cursor) -> bool {
+    matches!(cursor.first(), Some(Token::Int(_) | Token::OpenParen))
 }
 
 fn parse_condition(cursor: &mut Cursor) -> Result<Option<Condition>, DynError> {
-    match (cursor.first(), cursor.second()) {
-        (Some(Token::Less), Some(Token::Equals)) => {
-            cursor.bump();
-            cursor.bump();
-            let value = parse_expr(cursor)?;
-            Ok(Some(Condition::new(RelOp::LessEqual, value)))
-        }
-        (Some(Token::Greater), Some(Token::Equals)) => {
-            cursor.bump();
-            cursor.bump();
-            let value = parse_expr(cursor)?;
-            Ok(Some(Condition::new(RelOp::GreaterEqual, value)))
-        }
-        (Some(Token::Tilde), Some(Token::Equals)) => {
-            cursor.bump();
-            cursor.bump();
-            let value = parse_expr(cursor)?;
-            Ok(Some(Condition::new(RelOp::NotEquals, value)))
-        }
-        (Some(Token::Less), Some(_)) => {
-            cursor.bump();
-            let value = parse_expr(cursor)?;
-            Ok(Some(Condition::new(RelOp::Less, value)))
-        }
-        (Some(Token::Greater), Some(_)) => {
-            cursor.bump();
-            let value = parse_expr(cursor)?;
-            Ok(Some(Condition::new(RelOp::Greater, value)))
-        }
-        (Some(Token::Equals), Some(_)) => {
-            cursor.bump();
-            let value = parse_expr(cursor)?;
-            Ok(Some(Condition::new(RelOp::Equals, value)))
+    match parse_rel_op(cursor) {
+        Some(operator) => {
+            let value = parse_additive(cursor)?;
+            Ok(Some(Condition::new(operator, value)))
         }
-        _ => Ok(None),
+        None => Ok(None),
     }
 }
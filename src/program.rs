@@ -1,16 +1,21 @@
 use std::{
     error::Error,
+    fs,
     io::{Read, Write},
+    path::Path,
     sync::{Arc, Mutex},
 };
 
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 
 use crate::{
-    cli::Cli,
-    evaluator::{eval, EvalResult},
+    analyzer::analyze,
+    cli::{Cli, Command},
+    config::{Config, Limits, TableSource},
+    evaluator::{eval, Decks, Definitions, Env, EvalResult, Table},
     lexer::tokenize,
-    parser::{parse, Cursor},
+    parser::{parse, parse_signature, parse_statement, Cursor, Expr, Statement},
+    path_utils::get_path,
 };
 
 pub type DynError = Box<dyn Error + Send + Sync>;
@@ -20,79 +25,428 @@ pub fn run<R: Read, W: Write + Send + 'static>(
     writer: W,
     cli: &Cli,
 ) -> Result<(), DynError> {
+    if let Some(Command::Fmt { file }) = &cli.command {
+        return run_fmt(reader, writer, file.as_deref());
+    }
+
+    let config = Config::load(cli.config.as_deref())?;
+    let mut definitions = load_definitions(&config)?;
+
     // tokenize expression string
     let expression = cli.expression.join(" ");
 
-    if !expression.is_empty() {
-        run_amount(writer, &expression, cli)?;
+    if let Some(Command::Table { file }) = &cli.command {
+        let table = read_table(reader, file.as_deref())?;
+
+        // the table is named after its file in explanations, like `table(encounters, [4])`
+        let name = file
+            .as_deref()
+            .and_then(|file| Path::new(file).file_stem())
+            .map_or("table".to_string(), |stem| {
+                stem.to_string_lossy().to_string()
+            });
+
+        definitions.define_table(name.clone(), table);
+
+        let tree = Expr::Table { name, roll: None };
+
+        run_trees(writer, &[tree], cli, &definitions, &config.limits)?;
+    } else if cli.template {
+        run_template(reader, writer, cli, definitions, &config.limits)?;
+    } else if !expression.is_empty() {
+        run_amount(writer, &expression, cli, &definitions, &config.limits)?;
     } else {
-        run_lines(reader, writer, cli)?;
+        run_lines(reader, writer, cli, definitions, &config.limits)?;
     }
 
     Ok(())
 }
 
-fn run_amount<W: Write + Send + 'static>(writer: W, buf: &str, cli: &Cli) -> Result<(), DynError> {
-    let tokens = tokenize(buf)?;
+fn load_definitions(config: &Config) -> Result<Definitions, DynError> {
+    let mut definitions = Definitions::default();
+
+    for (signature, expression) in &config.macros {
+        let mut cursor = Cursor::new(tokenize(signature)?);
+        let (name, params) = parse_signature(&mut cursor)
+            .ok()
+            .filter(|_| cursor.first().is_none())
+            .ok_or_else(|| format!("Invalid macro name '{}'", signature))?;
+
+        let mut cursor = Cursor::new(tokenize(expression)?);
+        let body = parse(&mut cursor)
+            .and_then(analyze)
+            .map_err(|e| format!("In macro '{}': {}", name, e))?;
+
+        definitions.define(name, params, body)?;
+    }
+
+    for (name, source) in &config.tables {
+        let table = match source {
+            TableSource::File(path) => Table::load(path),
+            TableSource::Rows(rows) => Table::parse(&rows.join("\n")),
+        }
+        .map_err(|e| format!("In table '{}': {}", name, e))?;
+
+        definitions.define_table(name.clone(), table);
+    }
+
+    Ok(definitions)
+}
+
+/// Reads the table in `file`, or from the reader if there is no file.
+fn read_table<R: Read>(mut reader: R, file: Option<&str>) -> Result<Table, DynError> {
+    match file {
+        Some(file) => Table::load(file),
+        None => {
+            let mut buf = String::new();
+            reader.read_to_string(&mut buf)?;
+            Table::parse(&buf)
+        }
+    }
+}
+
+fn run_amount<W: Write + Send + 'static>(
+    writer: W,
+    buf: &str,
+    cli: &Cli,
+    definitions: &Definitions,
+    limits: &Limits,
+) -> Result<(), DynError> {
+    let mut trees = vec![];
+
+    for expression in buf.split(';').map(str::trim).filter(|s| !s.is_empty()) {
+        let tokens = tokenize(expression)?;
 
-    cli.verbose(|| dbg!(&tokens));
+        cli.verbose(|| eprintln!("{:?}", tokens));
+
+        // parse tokens
+        let mut cursor = Cursor::new(tokens);
+        let tree = analyze(parse(&mut cursor)?)?;
+        cli.verbose(|| eprintln!("{}", tree));
+        cli.verbose(|| eprintln!());
 
-    // parse tokens
-    let mut cursor = Cursor::new(tokens);
-    let tree = parse(&mut cursor)?;
-    cli.verbose(|| dbg!(&tree));
-    cli.verbose(|| eprintln!());
+        trees.push(tree);
+    }
+
+    run_trees(writer, &trees, cli, definitions, limits)
+}
+
+/// Evaluates `trees` as many times as asked for, joining the results of each evaluation with `; `.
+fn run_trees<W: Write + Send + 'static>(
+    writer: W,
+    trees: &[Expr],
+    cli: &Cli,
+    definitions: &Definitions,
+    limits: &Limits,
+) -> Result<(), DynError> {
+    let session_decks = Decks::default();
+
+    let eval_all = || -> Result<String, DynError> {
+        let evaluation_decks = Decks::default();
+        let decks = match cli.keep_decks {
+            true => &session_decks,
+            false => &evaluation_decks,
+        };
+
+        let results = trees
+            .iter()
+            .map(|tree| {
+                let result = eval(tree, &Env::new(cli, definitions, limits, decks))?;
+                Ok(format_result(result, cli))
+            })
+            .collect::<Result<Vec<_>, DynError>>()?;
+
+        Ok(results.join("; "))
+    };
 
     let writer = Arc::new(Mutex::new(writer));
 
     (0..cli.amount.unwrap_or(1) - 1)
         .into_par_iter()
         .try_for_each(|_| -> Result<_, DynError> {
-            let result = eval(&tree, cli)?;
+            let result = eval_all()?;
             let mut writer = writer.lock().unwrap();
-            writeln!(writer, "{}", format_result(result, cli)).map_err(|e| e.into())
+            writeln!(writer, "{}", result).map_err(|e| e.into())
         })?;
 
-    let result = eval(&tree, cli)?;
+    let result = eval_all()?;
     let mut writer = writer.lock().unwrap();
-    write!(writer, "{}", format_result(result, cli))?;
+    write!(writer, "{}", result)?;
     Ok(())
 }
 
-fn run_lines<R: Read, W: Write>(mut reader: R, mut writer: W, cli: &Cli) -> Result<(), DynError> {
+fn run_lines<R: Read, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    cli: &Cli,
+    mut definitions: Definitions,
+    limits: &Limits,
+) -> Result<(), DynError> {
     let mut buf = String::new();
     reader.read_to_string(&mut buf)?;
 
     let lines = buf.lines();
     let lines_count = lines.clone().count();
 
+    let session_decks = Decks::default();
+
     for (i, line) in buf.lines().enumerate() {
-        if line.is_empty() {
+        let Line { label, source, .. } = split_line(line);
+
+        if source.is_empty() {
+            match label {
+                Some(label) => return Err(format!("Label '{}' has no expression", label).into()),
+                None => continue,
+            }
+        }
+
+        let line_decks = Decks::default();
+        let decks = match cli.keep_decks {
+            true => &session_decks,
+            false => &line_decks,
+        };
+
+        let Some(result) = run_statements(source, cli, &mut definitions, limits, decks)? else {
             continue;
+        };
+
+        let output = match label {
+            Some(label) => format!("{}: {}", label, result),
+            None => result,
+        };
+
+        if i < lines_count - 1 {
+            writeln!(writer, "{}", output)?;
+        } else {
+            write!(writer, "{}", output)?;
         }
+    }
 
-        let tokens = tokenize(line)?;
+    Ok(())
+}
+
+/// Runs the `;`-separated statements in `source`, adding definitions to `definitions`.
+/// Returns the results joined by `; `, or `None` if every statement was a definition.
+fn run_statements(
+    source: &str,
+    cli: &Cli,
+    definitions: &mut Definitions,
+    limits: &Limits,
+    decks: &Decks,
+) -> Result<Option<String>, DynError> {
+    let mut results = vec![];
 
-        cli.verbose(|| dbg!(&tokens));
+    for source in source.split(';').map(str::trim).filter(|s| !s.is_empty()) {
+        let tokens = tokenize(source)?;
+
+        cli.verbose(|| eprintln!("{:?}", tokens));
 
         // parse tokens
         let mut cursor = Cursor::new(tokens);
-        let tree = parse(&mut cursor)?;
-        cli.verbose(|| dbg!(&tree));
+        let statement = parse_statement(&mut cursor)?;
+        cli.verbose(|| eprintln!("{}", statement));
         cli.verbose(|| eprintln!());
 
-        if i < lines_count - 1 {
-            let result = eval(&tree, cli)?;
-            writeln!(writer, "{}", format_result(result, cli))?;
-        } else {
-            let result = eval(&tree, cli)?;
-            write!(writer, "{}", format_result(result, cli))?;
+        match statement {
+            Statement::Definition { name, params, body } => {
+                definitions.define(name, params, analyze(body)?)?;
+            }
+            Statement::Expr(tree) => {
+                let tree = analyze(tree)?;
+                let result = eval(&tree, &Env::new(cli, definitions, limits, decks))?;
+                results.push(format_result(result, cli));
+            }
+        }
+    }
+
+    Ok((!results.is_empty()).then(|| results.join("; ")))
+}
+
+/// Copies the reader to the writer, replacing every `[[...]]` placeholder with the
+/// result of the statements inside it. Definitions are replaced with nothing.
+fn run_template<R: Read, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    cli: &Cli,
+    mut definitions: Definitions,
+    limits: &Limits,
+) -> Result<(), DynError> {
+    let mut rest = String::new();
+    reader.read_to_string(&mut rest)?;
+
+    let mut rest = rest.as_str();
+    let session_decks = Decks::default();
+
+    while let Some(start) = rest.find("[[") {
+        write!(writer, "{}", &rest[..start])?;
+
+        let placeholder = &rest[start + 2..];
+        let end = placeholder_end(placeholder).ok_or("Unclosed '[[' in template")?;
+        let source = &placeholder[..end];
+
+        let placeholder_decks = Decks::default();
+        let decks = match cli.keep_decks {
+            true => &session_decks,
+            false => &placeholder_decks,
+        };
+
+        let result = run_statements(source, cli, &mut definitions, limits, decks)
+            .map_err(|e| format!("In [[{}]]: {}", source, e))?;
+
+        write!(writer, "{}", result.unwrap_or_default())?;
+        rest = &placeholder[end + 2..];
+    }
+
+    write!(writer, "{}", rest)?;
+    Ok(())
+}
+
+/// The index of the `]]` closing a placeholder. Brackets of dice like `d[1..6]`
+/// are skipped, so `[[d[1..6]]]` ends at the last two brackets.
+fn placeholder_end(placeholder: &str) -> Option<usize> {
+    let mut depth = 0;
+
+    for (i, c) in placeholder.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' if depth > 0 => depth -= 1,
+            ']' if placeholder[i + 1..].starts_with(']') => return Some(i),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// A line of a source file, like `Attack: 1d20 + 7 # longsword`.
+#[derive(Debug, PartialEq)]
+struct Line<'a> {
+    label: Option<&'a str>,
+    source: &'a str,
+    /// The comment, including its `#`.
+    comment: Option<&'a str>,
+}
+
+/// Splits off the comment after a `#` and the label before a `:`. Labels can be
+/// any text, like `Damage [slashing]`, since `:` is not part of the notation.
+fn split_line(line: &str) -> Line<'_> {
+    let (code, comment) = match line.find('#') {
+        Some(i) => (&line[..i], Some(line[i..].trim_end())),
+        None => (line, None),
+    };
+
+    let (label, source) = match label_end(code) {
+        Some(i) => (Some(code[..i].trim()), &code[i + 1..]),
+        None => (None, code),
+    };
+
+    Line {
+        label,
+        source: source.trim(),
+        comment,
+    }
+}
+
+/// The index of the `:` which ends the label of `code`, skipping the `:`s
+/// inside weighted sides like `d{1: 1, 6: 4}`.
+fn label_end(code: &str) -> Option<usize> {
+    let mut depth = 0;
+
+    for (i, c) in code.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            ':' if depth == 0 => return Some(i),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Formats `file` in place, or the reader into the writer if there is no file.
+/// Nothing is written unless every line parses.
+fn run_fmt<R: Read, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    file: Option<&str>,
+) -> Result<(), DynError> {
+    match file {
+        Some(file) => {
+            let path = get_path(file)?;
+            let formatted = format_source(&fs::read_to_string(&path)?)?;
+            fs::write(&path, formatted)?;
+        }
+        None => {
+            let mut buf = String::new();
+            reader.read_to_string(&mut buf)?;
+            write!(writer, "{}", format_source(&buf)?)?;
         }
     }
 
     Ok(())
 }
 
+fn format_source(source: &str) -> Result<String, DynError> {
+    let mut formatted = String::new();
+
+    for (i, line) in source.lines().enumerate() {
+        let Line {
+            label,
+            source,
+            comment,
+        } = split_line(line);
+
+        let mut parts = vec![];
+
+        if let Some(label) = label {
+            parts.push(format!("{}:", label));
+        }
+
+        if !source.is_empty() {
+            let statements = source
+                .split(';')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(format_statement)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("Line {}: {}", i + 1, e))?;
+
+            parts.push(statements.join("; "));
+        }
+
+        parts.extend(comment.map(str::to_string));
+        formatted.push_str(&parts.join(" "));
+        formatted.push('\n');
+    }
+
+    if !source.ends_with('\n') {
+        formatted.pop();
+    }
+
+    Ok(formatted)
+}
+
+fn format_statement(source: &str) -> Result<String, DynError> {
+    let mut cursor = Cursor::new(tokenize(source)?);
+    let statement = parse_statement(&mut cursor)?;
+    let formatted = statement.to_string();
+    validate(statement)?;
+
+    Ok(formatted)
+}
+
+/// Checks a statement once it was formatted, so the formatted output keeps the
+/// expression as written instead of folded.
+fn validate(statement: Statement) -> Result<(), DynError> {
+    match statement {
+        Statement::Definition { body, .. } => analyze(body)?,
+        Statement::Expr(expr) => analyze(expr)?,
+    };
+
+    Ok(())
+}
+
 fn format_result(result: EvalResult, cli: &Cli) -> String {
     if cli.explain {
         format!("{} : {}", result.result, result.explanation)
@@ -102,4 +456,66 @@ fn format_result(result: EvalResult, cli: &Cli) -> String {
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_placeholder_end() {
+        assert_eq!(placeholder_end("1d20+5]] to hit"), Some(6));
+        assert_eq!(placeholder_end("d[1..6]]]"), Some(7));
+        assert_eq!(placeholder_end("1d20"), None);
+    }
+
+    #[test]
+    fn test_format_source() {
+        assert_eq!(
+            format_source("Attack: d20+7; 2d6  # two\nbonus=2;d20+bonus\n").unwrap(),
+            "Attack: 1d20 + 7; 2d6 # two\nbonus = 2; 1d20 + bonus\n"
+        );
+        assert!(format_source("1d20; 2d6k3").is_err());
+    }
+
+    #[test]
+    fn test_split_line() {
+        assert_eq!(
+            split_line("Damage [slashing]: 1d8+4  # longsword"),
+            Line {
+                label: Some("Damage [slashing]"),
+                source: "1d8+4",
+                comment: Some("# longsword"),
+            }
+        );
+        assert_eq!(
+            split_line("Loaded: d{1: 1, 6: 4}"),
+            Line {
+                label: Some("Loaded"),
+                source: "d{1: 1, 6: 4}",
+                comment: None,
+            }
+        );
+        assert_eq!(
+            split_line("d{1: 1, 6: 4}"),
+            Line {
+                label: None,
+                source: "d{1: 1, 6: 4}",
+                comment: None,
+            }
+        );
+        assert_eq!(
+            split_line("  # just a comment"),
+            Line {
+                label: None,
+                source: "",
+                comment: Some("# just a comment"),
+            }
+        );
+        assert_eq!(
+            split_line("2d6"),
+            Line {
+                label: None,
+                source: "2d6",
+                comment: None,
+            }
+        );
+    }
+}